@@ -0,0 +1,78 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn git_commit() -> Option<String> {
+    let output = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8(output.stdout).ok()?.trim().to_string())
+}
+
+/// Files whose changes move HEAD: HEAD itself, for checkouts, and the branch
+/// it points to, for commits. The branch is in packed-refs until it's updated
+fn git_head_files(manifest_dir: &str) -> Vec<String> {
+    let git_dir = Path::new(manifest_dir).join(".git");
+    let mut files = vec![".git/HEAD".to_string()];
+    let head = fs::read_to_string(git_dir.join("HEAD")).unwrap_or_default();
+    if let Some(branch) = head.trim().strip_prefix("ref: ") {
+        files.push(format!(".git/{branch}"));
+        files.push(".git/packed-refs".to_string());
+    }
+    // Watching a missing file would rebuild every time
+    files.retain(|file| Path::new(manifest_dir).join(file).exists());
+    files
+}
+
+/// Version of the cloudflare crate, as resolved in Cargo.lock
+fn cloudflare_version(manifest_dir: &str) -> Option<String> {
+    let lock = fs::read_to_string(Path::new(manifest_dir).join("Cargo.lock")).ok()?;
+    let mut lines = lock
+        .lines()
+        .skip_while(|line| *line != r#"name = "cloudflare""#);
+    lines.next()?;
+    let version = lines.next()?.strip_prefix("version = ")?.trim_matches('"');
+    Some(version.to_string())
+}
+
+fn main() {
+    let manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+
+    // SOURCE_DATE_EPOCH allows reproducible builds
+    let build_timestamp = env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| match epoch.parse() {
+            Ok(epoch) => Some(epoch),
+            Err(e) => {
+                println!("cargo:warning=Ignoring SOURCE_DATE_EPOCH={epoch:?}: {e}");
+                None
+            }
+        })
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs()
+        });
+
+    println!(
+        "cargo:rustc-env=CF_DDNS_GIT_COMMIT={}",
+        git_commit().unwrap_or("unknown".to_string())
+    );
+    println!("cargo:rustc-env=CF_DDNS_BUILD_TIMESTAMP={build_timestamp}");
+    println!(
+        "cargo:rustc-env=CF_DDNS_CLOUDFLARE_VERSION={}",
+        cloudflare_version(&manifest_dir).unwrap_or("unknown".to_string())
+    );
+    for file in git_head_files(&manifest_dir) {
+        println!("cargo:rerun-if-changed={file}");
+    }
+    println!("cargo:rerun-if-changed=Cargo.lock");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+}
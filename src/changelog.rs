@@ -0,0 +1,49 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+
+use color_eyre::eyre::Context;
+use color_eyre::Result;
+use serde_json::json;
+
+use crate::client::{Action, RecordOutcome};
+
+/// Appends one JSON line per created/updated record to `path`
+pub fn append(path: &Path, outcomes: &[RecordOutcome]) -> Result<()> {
+    let timestamp = chrono::Utc::now().to_rfc3339();
+    let lines: String = outcomes
+        .iter()
+        .filter(|outcome| outcome.action != Action::Unchanged)
+        .map(|outcome| {
+            json!({
+                "timestamp": timestamp,
+                "fqdn": outcome.fqdn,
+                "type": outcome.type_,
+                "action": outcome.action.as_str(),
+                "old_ip": outcome.old_content,
+                "new_ip": (outcome.action != Action::Deleted).then_some(&outcome.content),
+                "changed": outcome.changes.names(),
+            })
+            .to_string()
+                + "\n"
+        })
+        .collect();
+
+    if lines.is_empty() {
+        return Ok(());
+    }
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .wrap_err_with(|| format!("Failed to create changelog directory {parent:?}"))?;
+    }
+
+    // A single write to a file opened in append mode isn't interleaved with
+    // writes from other processes
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| file.write_all(lines.as_bytes()))
+        .wrap_err_with(|| format!("Failed to append to changelog {path:?}"))
+}
@@ -1,81 +1,844 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::Debug;
+use std::net::IpAddr;
 use std::rc::Rc;
+use std::time::Duration;
 
 use cloudflare::endpoints::dns;
 use cloudflare::endpoints::zone;
 use cloudflare::framework::async_api::Client as CClient;
+use cloudflare::framework::auth::Credentials;
+use cloudflare::framework::endpoint::Endpoint;
+use cloudflare::framework::response::{ApiFailure, ApiResponse, ApiResult};
 use cloudflare::framework::Environment;
-use color_eyre::eyre::Context;
-use color_eyre::Result;
-use log::{debug, info, warn};
+use color_eyre::eyre::{bail, ensure, eyre, Context};
+use color_eyre::{Report, Result};
+use log::{debug, error, info, log, trace, warn};
+use reqwest::StatusCode;
+use serde::Serialize;
 
 use crate::config::*;
+use crate::doctor::Checklist;
+use crate::marker;
+use crate::ptr;
+use crate::snapshot::{Snapshot, SnapshotRecord};
 use crate::util::*;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Created,
+    Updated,
+    Unchanged,
+    Deleted,
+}
+
+impl Action {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Action::Created => "created",
+            Action::Updated => "updated",
+            Action::Unchanged => "unchanged",
+            Action::Deleted => "deleted",
+        }
+    }
+}
+
+/// Record types `commit_record` manages, with the config keys enabling them.
+/// Keep in sync when adding a type
+pub const SUPPORTED_TYPES: &[(&str, &str)] = &[
+    ("A", "a (enabled by default), origins"),
+    ("AAAA", "aaaa, origins"),
+    ("CNAME", "cname"),
+    ("NS", "ns"),
+    ("PTR", "ptr"),
+];
+
+#[derive(Debug, Clone)]
+pub struct RecordOutcome {
+    pub fqdn: String,
+    pub type_: &'static str,
+    pub action: Action,
+    pub content: String,
+    /// Content of the record before it was updated
+    pub old_content: Option<String>,
+    pub proxied: bool,
+    /// Fields that were updated
+    pub changes: Changes,
+    /// Id of the record, unknown for records created in a dry run
+    pub id: Option<String>,
+}
+
+/// Fields of an existing record that differ from the wanted ones
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Changes {
+    pub content: bool,
+    pub ttl: bool,
+    pub proxied: bool,
+    pub priority: bool,
+}
+
+impl Changes {
+    /// Whether any field changed
+    pub fn any(&self) -> bool {
+        self.content || self.ttl || self.proxied || self.priority
+    }
+
+    /// Names of the changed fields
+    pub fn names(&self) -> Vec<&'static str> {
+        [
+            (self.content, "content"),
+            (self.ttl, "ttl"),
+            (self.proxied, "proxied"),
+            (self.priority, "priority"),
+        ]
+        .into_iter()
+        .filter(|(changed, _)| *changed)
+        .map(|(_, name)| name)
+        .collect()
+    }
+}
+
+/// Describes the change to a record, e.g.
+/// `home.example.com A: 1.2.3.4 -> 5.6.7.8 (ttl 1->300, proxied true->false)`.
+/// Only changed fields are shown. Records that don't exist yet (`old` is
+/// `None`) are shown as `home.example.com (create) A: 5.6.7.8`. The priority
+/// is only shown when set, as the record's is kept otherwise
+fn format_diff(
+    fqdn: &str,
+    type_: &str,
+    old: Option<(&dns::DnsRecord, &str, Option<u16>)>,
+    content: &str,
+    ttl: u32,
+    proxied: Option<bool>,
+    priority: Option<u16>,
+) -> String {
+    let Some((record, old_content, old_priority)) = old else {
+        return format!("{fqdn} (create) {type_}: {content}");
+    };
+
+    let mut diff = if old_content == content {
+        format!("{fqdn} {type_}: {content}")
+    } else {
+        format!("{fqdn} {type_}: {old_content} -> {content}")
+    };
+
+    let mut fields = Vec::new();
+    if record.ttl != ttl {
+        fields.push(format!("ttl {}->{ttl}", record.ttl));
+    }
+    if let Some(proxied) = proxied.filter(|proxied| *proxied != record.proxied) {
+        fields.push(format!("proxied {}->{proxied}", record.proxied));
+    }
+    if let Some(priority) = priority.filter(|priority| Some(*priority) != old_priority) {
+        let old_priority = old_priority.map_or_else(|| "none".to_string(), |old| old.to_string());
+        fields.push(format!("priority {old_priority}->{priority}"));
+    }
+    if !fields.is_empty() {
+        diff += &format!(" ({})", fields.join(", "));
+    }
+    diff
+}
+
+/// Resolves a setting of `subdomain` from its own and the global config,
+/// logging each step to the `cf_ddns::resolve` target
+fn resolve<T: Copy + Debug>(
+    subdomain: &str,
+    field: &str,
+    per_subdomain: Option<T>,
+    global: Option<T>,
+    default: T,
+) -> T {
+    let value = per_subdomain.or(global).unwrap_or(default);
+    debug!(
+        target: "cf_ddns::resolve",
+        "{subdomain}: {field}: per-subdomain {per_subdomain:?} → global {global:?} → \
+        used {value:?}"
+    );
+    value
+}
+
+/// Like [`resolve`], for settings that may be left unset
+fn resolve_optional<T: Copy + Debug>(
+    subdomain: &str,
+    field: &str,
+    per_subdomain: Option<T>,
+    global: Option<T>,
+) -> Option<T> {
+    let value = per_subdomain.or(global);
+    debug!(
+        target: "cf_ddns::resolve",
+        "{subdomain}: {field}: per-subdomain {per_subdomain:?} → global {global:?} → \
+        used {value:?}"
+    );
+    value
+}
+
+/// Zone id and name of `config`, or the global ones if it sets neither
+fn configured_zone<'a>(
+    config: &'a SubdomainsConfig,
+    global: &'a SubdomainsConfig,
+) -> (Option<&'a str>, Option<&'a str>) {
+    let config = if config.zone_id.is_some() || config.zone_name.is_some() {
+        config
+    } else {
+        global
+    };
+    (config.zone_id.as_deref(), config.zone_name.as_deref())
+}
+
+/// Fully qualified name of the subdomain `name` of `base_domain_name`. Names
+/// with several labels (`a.b`) are kept as is, and names that are already
+/// fully qualified don't get the base domain appended again
+fn fqdn(name: &str, base_domain_name: &str) -> String {
+    let name = name.trim_end_matches('.');
+    let lowercase = name.to_ascii_lowercase();
+    if matches!(name, "" | "@") || lowercase == base_domain_name {
+        base_domain_name.to_string()
+    } else if lowercase.ends_with(&format!(".{base_domain_name}")) {
+        let subdomain = &name[..name.len() - base_domain_name.len()];
+        format!("{subdomain}{base_domain_name}")
+    } else {
+        format!("{name}.{base_domain_name}")
+    }
+}
+
+/// Whether the records of `ip_version` (or any record if `None`) of a
+/// subdomain are proxied, with `schedules` the result of the subdomain's and
+/// global proxied_schedule. The subdomain's schedule and settings take
+/// precedence over --proxied (`cli`), which takes precedence over the global
+/// ones. The type-specific settings (proxied_a/proxied_aaaa) take precedence
+/// over the generic one of the same level
+fn resolve_proxied(
+    subdomain: &str,
+    ip_version: Option<IP>,
+    config: &SubdomainsConfig,
+    global: &SubdomainsConfig,
+    cli: Option<bool>,
+    (subdomain_schedule, global_schedule): (Option<bool>, Option<bool>),
+) -> bool {
+    let (field, per_subdomain, per_global) = match ip_version {
+        None => ("proxied", None, None),
+        Some(IP::V4) => ("proxied_a", config.proxied_a, global.proxied_a),
+        Some(IP::V6) => ("proxied_aaaa", config.proxied_aaaa, global.proxied_aaaa),
+    };
+    let per_subdomain = per_subdomain.or(config.proxied);
+    let per_global = per_global.or(global.proxied);
+    let proxied = subdomain_schedule
+        .or(per_subdomain)
+        .or(cli)
+        .or(global_schedule)
+        .or(per_global)
+        .unwrap_or(true);
+    debug!(
+        target: "cf_ddns::resolve",
+        "{subdomain}: {field}: per-subdomain schedule {subdomain_schedule:?} → per-subdomain \
+        {per_subdomain:?} → --proxied {cli:?} → global schedule {global_schedule:?} → global \
+        {per_global:?} → used {proxied}",
+    );
+    proxied
+}
+
+/// Name of the record of `subdomain`, relative to the zone unless it is fully
+/// qualified. Lowercased unless `preserve_case` is set, for providers that
+/// check the case of the names
+fn record_name(subdomain: &str, preserve_case: bool) -> String {
+    let name = subdomain.trim();
+    if preserve_case {
+        name.to_string()
+    } else {
+        name.to_lowercase()
+    }
+}
+
+/// Fails if the subdomain `name` is fully qualified in another of the
+/// `known_zones` than `zone_name`, e.g. `home.example.org` in example.com's
+/// zone, which would be published as `home.example.org.example.com`
+fn check_zone<'a>(
+    name: &str,
+    zone_name: &str,
+    known_zones: impl IntoIterator<Item = &'a String>,
+) -> Result<()> {
+    let name = normalize_zone_name(name);
+    let in_zone = |zone: &str| name == zone || name.ends_with(&format!(".{zone}"));
+    if in_zone(zone_name) {
+        return Ok(());
+    }
+    for zone in known_zones {
+        let zone = normalize_zone_name(zone);
+        ensure!(
+            !in_zone(&zone),
+            "{name} belongs to zone {zone}, not to {zone_name}"
+        );
+    }
+    Ok(())
+}
+
+/// Zone names in `config`, which subdomains are checked against whatever
+/// zones were fetched before them. Zones only given by id aren't known
+fn configured_zones(config: &Config) -> impl Iterator<Item = &String> {
+    let per_subdomain = config.subdomains.values().flat_map(|subdomain| {
+        subdomain
+            .zone_name
+            .iter()
+            .chain(subdomain.zone_names.iter().flatten())
+    });
+    config
+        .base_domain
+        .iter()
+        .chain(&config.subdomains_config.zone_name)
+        .chain(per_subdomain)
+}
+
+/// Record to publish at a name, written over the existing record it replaces
+#[derive(Debug, Clone)]
+struct WantedRecord {
+    type_: &'static str,
+    content: dns::DnsContent,
+    ttl: u32,
+    /// `None` for types that can't be proxied
+    proxied: Option<bool>,
+    /// Whether the ttl and proxied status of an existing record are changed,
+    /// or kept as they are
+    manage_ttl: bool,
+    manage_proxied: bool,
+    /// Left as it is when unset
+    priority: Option<u16>,
+    comment: Option<String>,
+}
+
+impl WantedRecord {
+    /// Ttl and proxied status to set on a record that has `ttl` and `proxied`.
+    /// Fields that aren't managed keep their current value
+    fn ttl_proxied(&self, ttl: u32, proxied: bool) -> (u32, Option<bool>) {
+        let ttl = if self.manage_ttl { self.ttl } else { ttl };
+        let proxied = self
+            .proxied
+            .map(|wanted| if self.manage_proxied { wanted } else { proxied });
+        (ttl, proxied)
+    }
+
+    /// Fields of a record with `content`, `ttl`, `proxied` and `priority`
+    /// that need to be changed. Contents are compared ignoring case, as they
+    /// are hostnames for the types that aren't addresses
+    fn changes(&self, content: &str, ttl: u32, proxied: bool, priority: Option<u16>) -> Changes {
+        let (wanted_ttl, wanted_proxied) = self.ttl_proxied(ttl, proxied);
+        Changes {
+            content: !content.eq_ignore_ascii_case(&content_string(&self.content)),
+            ttl: ttl != wanted_ttl,
+            proxied: wanted_proxied.is_some_and(|wanted| wanted != proxied),
+            priority: self.priority.is_some_and(|wanted| Some(wanted) != priority),
+        }
+    }
+}
+
+/// Whether the record `id` carries the cf-ddns marker in its comment
+fn has_marker(id: &str, extras: &[marker::RecordExtra]) -> bool {
+    extras
+        .iter()
+        .any(|extra| extra.id == id && extra.has_marker())
+}
+
+/// Whether a record carrying `tags` is owned by cf-ddns: any record is without
+/// an owner tag
+fn is_owned(owner_tag: Option<&str>, tags: &[String]) -> bool {
+    match owner_tag {
+        Some(owner_tag) => tags.iter().any(|tag| tag == owner_tag),
+        None => true,
+    }
+}
+
+/// Whether Cloudflare refused the request because the token lacks permission
+fn is_forbidden(error: &Report) -> bool {
+    error.chain().any(|e| {
+        matches!(
+            e.downcast_ref::<ApiFailure>(),
+            Some(ApiFailure::Error(StatusCode::FORBIDDEN, _))
+        )
+    })
+}
+
+/// Whether creating a record may have failed because it exists already.
+/// Cloudflare reports it with a 400 and error codes (81057, 81058) that don't
+/// fit the crate's error type, which leaves the errors empty
+fn may_already_exist(failure: &ApiFailure) -> bool {
+    match failure {
+        ApiFailure::Error(StatusCode::BAD_REQUEST, errors) => {
+            errors.errors.is_empty()
+                || errors
+                    .errors
+                    .iter()
+                    .any(|error| error.message.contains("already exists"))
+        }
+        _ => false,
+    }
+}
+
+/// Content of an A or AAAA record for `ip`
+fn address_content(ip: IpAddr) -> dns::DnsContent {
+    match ip {
+        IpAddr::V4(content) => dns::DnsContent::A { content },
+        IpAddr::V6(content) => dns::DnsContent::AAAA { content },
+    }
+}
+
+/// Content of a record of one of the managed types as text
+fn content_string(content: &dns::DnsContent) -> String {
+    match content {
+        dns::DnsContent::A { content } => content.to_string(),
+        dns::DnsContent::AAAA { content } => content.to_string(),
+        dns::DnsContent::CNAME { content } | dns::DnsContent::NS { content } => content.clone(),
+        _ => String::new(),
+    }
+}
+
+/// Body of the request creating the record `fqdn`
+fn create_params(
+    fqdn: &str,
+    content: dns::DnsContent,
+    ttl: u32,
+    proxied: Option<bool>,
+    priority: Option<u16>,
+) -> dns::CreateDnsRecordParams<'_> {
+    dns::CreateDnsRecordParams {
+        content,
+        name: fqdn,
+        proxied,
+        ttl: Some(ttl),
+        priority,
+    }
+}
+
+/// Body of the request updating a record to `fqdn` and `content`
+fn update_params(
+    fqdn: &str,
+    content: dns::DnsContent,
+    ttl: u32,
+    proxied: Option<bool>,
+) -> dns::UpdateDnsRecordParams<'_> {
+    dns::UpdateDnsRecordParams {
+        ttl: Some(ttl),
+        proxied,
+        name: fqdn,
+        content,
+    }
+}
+
 pub struct Client {
     pub config: Rc<Config>,
-    authed_client: CClient,
+    authed_client: Rc<CClient>,
+    /// Clients for subdomains with their own api_token or timeout
+    token_clients: HashMap<(Option<String>, Option<Duration>), Rc<CClient>>,
     zone_id_cache: HashMap<String, String>,
-    ip_cache: [Option<String>; 2],
+    /// Zones accessible with each api_token (`None` for the global credentials)
+    zones: HashMap<Option<String>, Vec<zone::Zone>>,
+    ip_cache: HashMap<(IP, IpSource, Option<IpAddr>), String>,
+    /// Detections that failed in this run, so they aren't attempted again for
+    /// every subdomain
+    ip_failures: HashMap<(IP, IpSource, Option<IpAddr>), String>,
+    /// Used instead of the built-in detection when set
+    ip_provider: Option<Box<dyn IpProvider>>,
+    /// IPs of `ip_provider`, reused for the rest of the run
+    provider_ips: HashMap<IP, String>,
+    /// Runs started so far
+    runs: u64,
 }
 
 impl Client {
     pub fn new(config: Config) -> Result<Self> {
         let authed_client = CClient::new(
             config.cloudflare.auth.clone(),
-            Default::default(),
+            config.cloudflare.http_config(),
             Environment::Production,
         )?;
 
+        let mut zone_id_cache = HashMap::new();
+        if let (Some(zone_id), Some(base_domain)) =
+            (&config.subdomains_config.zone_id, &config.base_domain)
+        {
+            zone_id_cache.insert(zone_id.clone(), base_domain.clone());
+        }
+
         Ok(Client {
             config: Rc::new(config),
-            authed_client,
-            zone_id_cache: Default::default(),
+            authed_client: Rc::new(authed_client),
+            token_clients: Default::default(),
+            zone_id_cache,
+            zones: Default::default(),
             ip_cache: Default::default(),
+            ip_failures: Default::default(),
+            ip_provider: None,
+            provider_ips: Default::default(),
+            runs: 0,
         })
     }
 
-    pub async fn get_ip(&mut self, version: IP) -> Result<String> {
-        let idx = version as usize;
-        Ok(match &self.ip_cache[idx] {
-            Some(s) => s.clone(),
-            None => {
-                let ip = get_ip(version).await?;
-                self.ip_cache[idx] = Some(ip.clone());
-                ip
+    /// Sends `endpoint` with `api`, logging the request and response to the
+    /// `cf_ddns::api` target when API tracing is enabled
+    pub async fn request<ResultType, QueryType, BodyType>(
+        &self,
+        api: &CClient,
+        endpoint: &(dyn Endpoint<ResultType, QueryType, BodyType> + Send + Sync),
+    ) -> ApiResponse<ResultType>
+    where
+        ResultType: ApiResult,
+        QueryType: Serialize,
+        BodyType: Serialize,
+    {
+        if !self.config.trace_api {
+            return api.request(endpoint).await;
+        }
+
+        let path = endpoint.path();
+        trace!(
+            target: "cf_ddns::api",
+            "request {path}: query: {}, body: {}",
+            serde_json::to_string(&endpoint.query()).unwrap_or_default(),
+            serde_json::to_string(&endpoint.body()).unwrap_or_default(),
+        );
+        let response = api.request(endpoint).await;
+        match &response {
+            Ok(success) => trace!(target: "cf_ddns::api", "response {path}: {success:?}"),
+            Err(failure) => trace!(target: "cf_ddns::api", "response {path}: {failure:?}"),
+        }
+        response
+    }
+
+    /// Client authenticated with `api_token`, or the global credentials if
+    /// unset. `timeout` overrides the timeout of its requests
+    pub fn api_client(
+        &mut self,
+        api_token: Option<&str>,
+        timeout: Option<Duration>,
+    ) -> Result<Rc<CClient>> {
+        if api_token.is_none() && timeout.is_none() {
+            return Ok(self.authed_client.clone());
+        }
+
+        let key = (api_token.map(str::to_string), timeout);
+        if let Some(client) = self.token_clients.get(&key) {
+            return Ok(client.clone());
+        }
+
+        let auth = match api_token {
+            Some(token) => Credentials::UserAuthToken {
+                token: token.to_string(),
+            },
+            None => self.config.cloudflare.auth.clone(),
+        };
+        let mut http_config = self.config.cloudflare.http_config();
+        if let Some(timeout) = timeout {
+            http_config.http_timeout = timeout;
+        }
+        let client = Rc::new(CClient::new(auth, http_config, Environment::Production)?);
+        self.token_clients.insert(key, client.clone());
+        Ok(client)
+    }
+
+    /// Detected IP, reused for the rest of the run. `timeout` overrides the
+    /// detection timeout
+    pub async fn get_ip(
+        &mut self,
+        version: IP,
+        source: IpSource,
+        source_address: Option<IpAddr>,
+        timeout: Option<Duration>,
+    ) -> Result<String> {
+        if let Some(provider) = &self.ip_provider {
+            if let Some(ip) = self.provider_ips.get(&version) {
+                return Ok(ip.clone());
             }
-        })
+            let ip = provider.detect(version).await?.to_string();
+            self.provider_ips.insert(version, ip.clone());
+            return Ok(ip);
+        }
+
+        let key = (version, source, source_address);
+        if let Some(ip) = self.ip_cache.get(&key) {
+            return Ok(ip.clone());
+        }
+        if let Some(e) = self.ip_failures.get(&key) {
+            if let Some(ip) = self.last_known_ip(version) {
+                return Ok(ip);
+            }
+            return Err(eyre!("{version} detection already failed in this run: {e}"))
+                .wrap_err(crate::exit::ErrorKind::Detection);
+        }
+
+        let timeout = timeout.unwrap_or(self.config.detection.timeout);
+        let detection = &self.config.detection;
+        let mut detected = builtin_provider(source, detection, source_address, timeout)
+            .detect(version)
+            .await;
+        if let (Ok(ip), false) = (&detected, source == IpSource::Local) {
+            if let Err(e) = detection.expect.check(*ip) {
+                detected = if source == IpSource::Providers || detection.providers.is_empty() {
+                    Err(e).wrap_err_with(|| format!("{source:?} source reported a bogus {version}"))
+                } else {
+                    warn!(
+                        "{source:?} source reported a bogus {version}, asking the providers \
+                        instead: {e:#}"
+                    );
+                    builtin_provider(IpSource::Providers, detection, source_address, timeout)
+                        .detect(version)
+                        .await
+                };
+            }
+        }
+        let detected = detected.and_then(|ip| self.check_detected_ip(version, ip).map(|()| ip));
+        match detected {
+            Ok(ip) => {
+                let ip = ip.to_string();
+                self.ip_cache.insert(key, ip.clone());
+                Ok(ip)
+            }
+            Err(e) => {
+                self.ip_failures.insert(key, format!("{e:#}"));
+                if let Some(ip) = self.last_known_ip(version) {
+                    warn!("{version} detection failed, using the last known {ip}: {e:#}");
+                    return Ok(ip);
+                }
+                Err(e).wrap_err(crate::exit::ErrorKind::Detection)
+            }
+        }
+    }
+
+    /// IP of a previous run from --write-ip-file, with
+    /// --use-last-known-on-failure. It isn't cached, so the file isn't
+    /// rewritten with it and it eventually gets too old to be used
+    fn last_known_ip(&self, version: IP) -> Option<String> {
+        let max_age = self.config.last_known_max_age?;
+        let path = self.config.write_ip_file.as_ref()?;
+        match read_ip_file(path, version, max_age) {
+            Ok(ip) => Some(ip),
+            Err(e) => {
+                warn!("No last known {version} to use: {e:#}");
+                None
+            }
+        }
+    }
+
+    /// Refuses a detected IP that isn't one of --expect-current-ip, or that is
+    /// further than --max-change-abs from the one in --write-ip-file. Only the
+    /// built-in detection is checked: the IPs of a custom provider, like the
+    /// ones records are reset to on shutdown, are used as they are
+    fn check_detected_ip(&self, version: IP, ip: IpAddr) -> Result<()> {
+        let expected: Vec<_> = self
+            .config
+            .expect_current_ip
+            .iter()
+            .filter(|expected| expected.is_ipv4() == ip.is_ipv4())
+            .collect();
+        ensure!(
+            expected.is_empty() || expected.contains(&&ip),
+            "Detected {version} {ip} isn't one of the expected {expected:?}"
+        );
+
+        let (Some(max_change), Some(path)) =
+            (self.config.max_change_abs, &self.config.write_ip_file)
+        else {
+            return Ok(());
+        };
+        let previous: IpAddr = match read_ip_file(path, version, Duration::MAX) {
+            Ok(previous) => previous.parse()?,
+            Err(e) => {
+                debug!("No previous {version} to compare {ip} with: {e:#}");
+                return Ok(());
+            }
+        };
+        let change = match (previous, ip) {
+            (IpAddr::V4(previous), IpAddr::V4(ip)) => {
+                u128::from(u32::from(previous).abs_diff(u32::from(ip)))
+            }
+            (IpAddr::V6(previous), IpAddr::V6(ip)) => u128::from(previous).abs_diff(u128::from(ip)),
+            _ => return Ok(()),
+        };
+        ensure!(
+            change <= max_change,
+            "Detected {version} {ip} is {change} addresses away from the previous {previous}, \
+            more than --max-change-abs {max_change}"
+        );
+        Ok(())
+    }
+
+    /// Counts a new run, which decides whether unchanged records are logged
+    pub fn start_run(&mut self) {
+        self.runs += 1;
+    }
+
+    /// Unchanged records are only logged at info level every
+    /// --log-unchanged-every runs, starting with the first one
+    fn unchanged_level(&self) -> log::Level {
+        let every = self.config.log_unchanged_every.unwrap_or(1);
+        match self.runs.saturating_sub(1) % every {
+            0 => log::Level::Info,
+            _ => log::Level::Debug,
+        }
+    }
+
+    /// Forgets the detected IPs and failures so the next run detects them again
+    pub fn clear_detected_ips(&mut self) {
+        self.ip_cache.clear();
+        self.ip_failures.clear();
+        self.provider_ips.clear();
+    }
+
+    /// Replaces the built-in detection for all records, or restores it if
+    /// `None`. The IPs of `provider` are reused until the next run
+    pub fn set_ip_provider(&mut self, provider: Option<Box<dyn IpProvider>>) {
+        self.ip_provider = provider;
+        self.provider_ips.clear();
+    }
+
+    /// IPs detected so far in this run
+    pub fn detected_ips(&self) -> impl Iterator<Item = (IP, &str)> {
+        let mut ips: Vec<_> = self
+            .ip_cache
+            .iter()
+            .map(|((version, _, _), ip)| (*version, ip.as_str()))
+            .collect();
+        ips.sort_by_key(|(version, ip)| (*version as u8, *ip));
+        ips.dedup();
+        ips.into_iter()
     }
 
-    pub async fn get_zone_details(&mut self, zone_id: &str) -> Result<String> {
+    pub async fn get_zone_details(&mut self, api: &CClient, zone_id: &str) -> Result<String> {
         if let Some(zone_details) = self.zone_id_cache.get(zone_id) {
             return Ok(zone_details.clone());
         };
 
-        let zone_details = self
-            .authed_client
-            .request(&zone::ZoneDetails {
-                identifier: zone_id,
-            })
+        let zone_details = match self
+            .request(
+                api,
+                &zone::ZoneDetails {
+                    identifier: zone_id,
+                },
+            )
             .await
-            .with_context(|| format!("Failed to get zone details (zone: {zone_id})"))?;
+        {
+            Ok(zone_details) => zone_details,
+            Err(e) => {
+                let forbidden = matches!(&e, ApiFailure::Error(StatusCode::FORBIDDEN, _));
+                let e = Report::new(e)
+                    .wrap_err(format!("Failed to get zone details (zone: {zone_id})"));
+                if forbidden {
+                    return Err(e.wrap_err(format!(
+                        "The token can't read zone {zone_id}. Pass --base-domain or set zone_name \
+                        along with zone_id so the zone doesn't need to be read"
+                    )));
+                }
+                return Err(e);
+            }
+        };
+
+        // Names are joined to the subdomains, so a trailing dot would end up in
+        // the middle of the fqdn
+        let name = normalize_zone_name(&zone_details.result.name);
+        self.zone_id_cache.insert(zone_id.to_string(), name.clone());
+        Ok(name)
+    }
+
+    /// Forgets the listed zones so they are fetched again
+    pub fn clear_zones(&mut self) {
+        self.zones.clear();
+    }
+
+    /// Every zone accessible with `api_token` (or the global credentials if
+    /// unset), following pagination. Fetched once and reused for all lookups
+    async fn list_zones(&mut self, api_token: Option<&str>) -> Result<&[zone::Zone]> {
+        const PER_PAGE: u32 = 50;
+
+        let key = api_token.map(str::to_string);
+        if !self.zones.contains_key(&key) {
+            let api = self.api_client(api_token, None)?;
+            let mut zones = Vec::new();
+            for page in 1.. {
+                let page_zones = self
+                    .request(
+                        &api,
+                        &zone::ListZones {
+                            params: zone::ListZonesParams {
+                                page: Some(page),
+                                per_page: Some(PER_PAGE),
+                                ..Default::default()
+                            },
+                        },
+                    )
+                    .await
+                    .wrap_err("Failed to list zones")?
+                    .result;
+
+                let last_page = page_zones.len() < PER_PAGE as usize;
+                zones.extend(page_zones);
+                if last_page {
+                    break;
+                }
+            }
+            debug!("Listed {} zones", zones.len());
+
+            for zone in &zones {
+                self.zone_id_cache
+                    .insert(zone.id.clone(), normalize_zone_name(&zone.name));
+            }
+            self.zones.insert(key.clone(), zones);
+        }
+        Ok(&self.zones[&key])
+    }
+
+    /// Looks up the id of the zone named `zone_name`, restricted to the
+    /// configured account if there is one
+    pub async fn get_zone_id(
+        &mut self,
+        api_token: Option<&str>,
+        zone_name: &str,
+    ) -> Result<String> {
+        let zone_name = normalize_zone_name(zone_name);
+        let config = self.config.clone();
+        let account_id = config.cloudflare.account_id.as_deref();
+        let zones: Vec<_> = self
+            .list_zones(api_token)
+            .await?
+            .iter()
+            .filter(|zone| normalize_zone_name(&zone.name) == zone_name)
+            .filter(|zone| account_id.is_none() || account_id == Some(zone.account.id.as_str()))
+            .collect();
+
+        let zone = match &zones[..] {
+            [zone] => zone,
+            [] => match account_id {
+                Some(account_id) => bail!("Zone {zone_name} not found in account {account_id}"),
+                None => bail!("Zone {zone_name} not found"),
+            },
+            _ => bail!(
+                "Zone {zone_name} found in multiple accounts ({}), specify account_id or zone_id",
+                zones
+                    .iter()
+                    .map(|zone| zone.account.id.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        };
 
-        self.zone_id_cache
-            .insert(zone_id.to_string(), zone_details.result.name.clone());
-        Ok(zone_details.result.name)
+        Ok(zone.id.clone())
     }
 
-    pub async fn get_dns_records(&self, zone_id: &str, fqdn: &str) -> Result<Vec<dns::DnsRecord>> {
+    pub async fn get_dns_records(
+        &self,
+        api: &CClient,
+        zone_id: &str,
+        fqdn: &str,
+    ) -> Result<Vec<dns::DnsRecord>> {
         let records = self
-            .authed_client
-            .request(&dns::ListDnsRecords {
-                zone_identifier: zone_id,
-                params: dns::ListDnsRecordsParams {
-                    per_page: Some(100),
-                    name: Some(fqdn.to_string()),
-                    ..Default::default()
+            .request(
+                api,
+                &dns::ListDnsRecords {
+                    zone_identifier: zone_id,
+                    params: dns::ListDnsRecordsParams {
+                        per_page: Some(100),
+                        name: Some(fqdn.to_string()),
+                        ..Default::default()
+                    },
                 },
-            })
+            )
             .await
             .with_context(|| {
                 format!("Failed to get dns records (zone: {zone_id}, name: {fqdn})")
@@ -83,149 +846,2143 @@ impl Client {
         Ok(records.result)
     }
 
-    pub async fn commit_record(
-        &mut self,
-        subdomain: &str,
-        config: &SubdomainsConfig,
-    ) -> Result<()> {
-        debug!("[commit_record] subdomain: {subdomain}");
-        let zone_id = config
-            .zone_id
-            .as_ref()
-            .or(self.config.subdomains_config.zone_id.as_ref())
-            .expect("zone_id is None even after checks")
-            .to_string();
-        let base_domain_name = self.get_zone_details(&zone_id).await?;
-        debug!("Base domain name: {base_domain_name}");
+    /// Lists every record in the zone, following pagination
+    pub async fn get_zone_records(
+        &self,
+        api: &CClient,
+        zone_id: &str,
+    ) -> Result<Vec<dns::DnsRecord>> {
+        const PER_PAGE: u32 = 100;
 
-        let name = subdomain.to_lowercase();
-        let name = name.trim();
-        let fqdn = if !matches!(name, "" | "@") {
-            format!("{name}.{base_domain_name}")
-        } else {
-            base_domain_name
-        };
-        debug!("fqdn: {fqdn}");
+        let mut records = Vec::new();
+        for page in 1.. {
+            let page_records = self
+                .request(
+                    api,
+                    &dns::ListDnsRecords {
+                        zone_identifier: zone_id,
+                        params: dns::ListDnsRecordsParams {
+                            page: Some(page),
+                            per_page: Some(PER_PAGE),
+                            ..Default::default()
+                        },
+                    },
+                )
+                .await
+                .with_context(|| format!("Failed to list dns records (zone: {zone_id})"))?
+                .result;
 
-        let a = config.a.or(self.config.subdomains_config.a).unwrap_or(true);
-        let aaaa = config
-            .aaaa
-            .or(self.config.subdomains_config.aaaa)
-            .unwrap_or(false);
+            let last_page = page_records.len() < PER_PAGE as usize;
+            records.extend(page_records);
+            if last_page {
+                break;
+            }
+        }
+        Ok(records)
+    }
 
-        if (a, aaaa) == (false, false) {
-            warn!("A = false and AAAA = false for subdomain {name}");
-            return Ok(());
+    /// Resolves the zone configured globally (in `[subdomains]` or arguments)
+    async fn get_global_zone_id(&mut self) -> Result<String> {
+        let global = self.config.clone();
+        match (
+            &global.subdomains_config.zone_id,
+            &global.subdomains_config.zone_name,
+        ) {
+            (Some(zone_id), _) => Ok(zone_id.clone()),
+            (None, Some(zone_name)) => self.get_zone_id(None, zone_name).await,
+            (None, None) => bail!("Neither zone_id nor zone_name specified"),
         }
+    }
 
-        let dns_records = self.get_dns_records(&zone_id, &fqdn).await?;
+    /// Generates config for the A/AAAA records that currently exist in the zone
+    pub async fn export(&mut self) -> Result<String> {
+        let api = self.authed_client.clone();
+        let zone_id = self.get_global_zone_id().await?;
+        let base_domain_name = self.get_zone_details(&api, &zone_id).await?;
+        let records = self.get_zone_records(&api, &zone_id).await?;
 
-        let proxied = config
-            .proxied
-            .or(self.config.subdomains_config.proxied)
-            .unwrap_or(true);
+        struct Exported {
+            a: bool,
+            aaaa: bool,
+            proxied: bool,
+            ttl: u32,
+        }
 
-        let ttl = config
-            .ttl
-            .or(self.config.subdomains_config.ttl)
-            .unwrap_or(1);
+        // Keep the order records are first seen in while merging A and AAAA
+        let mut names: Vec<&str> = Vec::new();
+        let mut exported: HashMap<&str, Exported> = HashMap::new();
+        for record in &records {
+            let (a, aaaa) = match record.content {
+                dns::DnsContent::A { .. } => (true, false),
+                dns::DnsContent::AAAA { .. } => (false, true),
+                _ => continue,
+            };
 
-        for (use_, type_, ip_version) in [(a, "A", IP::V4), (aaaa, "AAAA", IP::V6)] {
-            if !use_ {
-                continue;
-            }
+            let entry = exported.entry(&record.name).or_insert_with(|| {
+                names.push(&record.name);
+                Exported {
+                    a: false,
+                    aaaa: false,
+                    proxied: record.proxied,
+                    ttl: record.ttl,
+                }
+            });
+            entry.a |= a;
+            entry.aaaa |= aaaa;
+        }
 
-            if let Some((record, record_ip)) =
-                dns_records.iter().find_map(|record| match ip_version {
-                    IP::V4 => {
-                        if let dns::DnsContent::A { content } = record.content {
-                            Some((record, content.to_string()))
-                        } else {
-                            None
-                        }
-                    }
-                    IP::V6 => {
-                        if let dns::DnsContent::AAAA { content } = record.content {
-                            Some((record, content.to_string()))
-                        } else {
-                            None
-                        }
-                    }
-                })
-            {
-                let ip = self.get_ip(ip_version).await?;
+        let mut toml = format!("[subdomains]\nzone_id = {}\n", toml::Value::from(zone_id));
+        for name in names {
+            let Exported {
+                a,
+                aaaa,
+                proxied,
+                ttl,
+            } = exported[name];
+            let subdomain = if name == base_domain_name {
+                "@"
+            } else {
+                name.strip_suffix(&format!(".{base_domain_name}"))
+                    .unwrap_or(name)
+            };
 
-                let content = match ip_version {
-                    IP::V4 => dns::DnsContent::A {
-                        content: ip.parse().unwrap(),
-                    },
-                    IP::V6 => dns::DnsContent::AAAA {
-                        content: ip.parse().unwrap(),
-                    },
-                };
-                let id = &record.id;
+            toml += &format!(
+                "\n[subdomain.{}] # {name}\na = {a}\naaaa = {aaaa}\nproxied = {proxied}\n\
+                ttl = {ttl}\n",
+                toml::Value::from(subdomain)
+            );
+        }
+        Ok(toml)
+    }
 
-                if record.proxied == proxied && record_ip == ip && record.ttl == ttl {
-                    info!("{fqdn}: record {id} doesn't need to be modified");
-                } else {
-                    info!(
-                        "{fqdn}: updating {type_} record with id {id}. Old ip: {}",
-                        record_ip,
-                    );
-                    debug!("{fqdn}: old record: {record:?}");
-                    let record = self
-                        .authed_client
-                        .request(&dns::UpdateDnsRecord {
-                            identifier: id,
-                            zone_identifier: &zone_id,
-                            params: dns::UpdateDnsRecordParams {
-                                ttl: Some(ttl),
-                                proxied: Some(proxied),
-                                name: &fqdn,
-                                content,
-                            },
-                        })
-                        .await
-                        .with_context(|| format!("Failed to update {type_} record for {fqdn}"))?;
+    /// Checks that the credentials are valid, the zones of all subdomains
+    /// resolve and the IPs of the enabled record types can be detected, without
+    /// changing any records
+    pub async fn preflight(&mut self, checklist: &mut Checklist) {
+        let global = self.config.clone();
+        let mut subdomains: Vec<_> = global.subdomains.iter().collect();
+        subdomains.sort_by_key(|(subdomain, _)| *subdomain);
 
-                    info!("{fqdn}: succesfully updated {type_} record with id {id}. New ip: {ip}");
-                    debug!("{fqdn}: new record: {:?}", record.result);
-                }
-            } else {
-                info!("{fqdn}: {type_} record not found, creating it");
+        let mut checked_tokens = HashSet::new();
+        for (subdomain, config) in [("", &global.subdomains_config)].into_iter().chain(
+            subdomains
+                .iter()
+                .map(|(subdomain, config)| (subdomain.as_str(), *config)),
+        ) {
+            let token = config.api_token.as_deref();
+            if !checked_tokens.insert(token) {
+                continue;
+            }
+            let check = match token {
+                None => "credentials".to_string(),
+                Some(_) => format!("api_token of subdomain {subdomain:?}"),
+            };
+            checklist.check(
+                &check,
+                self.check_credentials(token)
+                    .await
+                    .map(|_| "valid".to_string()),
+                "make sure the token hasn't expired or been revoked",
+            );
+        }
 
-                let ip = self.get_ip(ip_version).await?;
-                let content = match ip_version {
-                    IP::V4 => dns::DnsContent::A {
-                        content: ip.parse().unwrap(),
-                    },
-                    IP::V6 => dns::DnsContent::AAAA {
-                        content: ip.parse().unwrap(),
-                    },
+        for (subdomain, config) in &subdomains {
+            for (_, config) in config.per_zone() {
+                let zone = match self.api_client(config.api_token.as_deref(), None) {
+                    Ok(api) => self.get_subdomain_zone(&api, &config).await,
+                    Err(e) => Err(e),
                 };
+                checklist.check(
+                    &format!("zone of subdomain {subdomain:?}"),
+                    zone.map(|(zone_id, zone_name)| format!("{zone_name} ({zone_id})")),
+                    "check zone_id/zone_name and that the token has Zone:Read access to the zone",
+                );
+            }
+        }
 
-                let record = self
-                    .authed_client
-                    .request(&dns::CreateDnsRecord {
-                        zone_identifier: &zone_id,
-                        params: dns::CreateDnsRecordParams {
-                            content,
-                            name: &fqdn,
-                            proxied: Some(proxied),
-                            ttl: Some(ttl),
-                            priority: None,
-                        },
-                    })
-                    .await
-                    .with_context(|| format!("Failed to create {type_} record for {fqdn}"))?;
-
-                info!(
-                    "{fqdn}: successfully created {type_} record. id: {}, ip: {:?}",
-                    record.result.id, record.result.content
+        let mut checked_ips = HashSet::new();
+        for (_, config) in &subdomains {
+            let source_address = config
+                .source_address
+                .or(global.subdomains_config.source_address);
+            for (_, version, source) in self.address_records(config) {
+                if !checked_ips.insert((version, source, source_address)) {
+                    continue;
+                }
+                let mut check = format!("{version} detection ({source:?})");
+                if let Some(source_address) = source_address {
+                    check += &format!(" from {source_address}");
+                }
+                checklist.check(
+                    &check,
+                    self.get_ip(version, source, source_address, None).await,
+                    &format!("check {version} connectivity, or disable the records that need it"),
                 );
             }
         }
+    }
+
+    /// Cheapest authenticated call, listing a single zone
+    async fn check_credentials(&mut self, api_token: Option<&str>) -> Result<()> {
+        let api = self.api_client(api_token, None)?;
+        self.request(
+            &api,
+            &zone::ListZones {
+                params: zone::ListZonesParams {
+                    per_page: Some(1),
+                    ..Default::default()
+                },
+            },
+        )
+        .await
+        .wrap_err("Failed to list zones")?;
+        Ok(())
+    }
+
+    /// Id and name of the zone a subdomain belongs to
+    async fn get_subdomain_zone(
+        &mut self,
+        api: &CClient,
+        config: &SubdomainsConfig,
+    ) -> Result<(String, String)> {
+        let global = self.config.clone();
+        let (zone_id, zone_name) = configured_zone(config, &global.subdomains_config);
+        let zone_id = match (zone_id, zone_name) {
+            // The name is checked against the zone's details, but tokens without
+            // zone read permission still work with the configured name
+            (Some(zone_id), Some(zone_name)) => {
+                let zone_name = normalize_zone_name(zone_name);
+                match self.get_zone_details(api, zone_id).await {
+                    Ok(name) => ensure!(
+                        name == zone_name,
+                        "zone_name is {zone_name}, but zone {zone_id} is {name}"
+                    ),
+                    Err(e) if is_forbidden(&e) => {
+                        warn!(
+                            "The token can't read zone {zone_id}, so it can't be checked to be \
+                            {zone_name}. zone_id is used along with zone_name as configured"
+                        );
+                        self.zone_id_cache
+                            .insert(zone_id.to_string(), zone_name.clone());
+                    }
+                    Err(e) => return Err(e),
+                }
+                return Ok((zone_id.to_string(), zone_name));
+            }
+            (Some(zone_id), None) => zone_id.to_string(),
+            (None, Some(zone_name)) => {
+                self.get_zone_id(config.api_token.as_deref(), zone_name)
+                    .await?
+            }
+            (None, None) => unreachable!("zone_id and zone_name are None even after checks"),
+        };
+        let base_domain_name = self.get_zone_details(api, &zone_id).await?;
+        Ok((zone_id, base_domain_name))
+    }
+
+    /// Zone of a subdomain as configured (its id or name), along with the
+    /// per-subdomain token it is accessed with. Subdomains with the same key
+    /// are in the same zone
+    pub fn zone_key(&self, config: &SubdomainsConfig) -> (Option<String>, String) {
+        let zone = match configured_zone(config, &self.config.subdomains_config) {
+            (Some(zone_id), _) => zone_id,
+            (None, Some(zone_name)) => zone_name,
+            (None, None) => unreachable!("zone_id and zone_name are None even after checks"),
+        };
+        (config.api_token.clone(), zone.to_string())
+    }
 
+    /// Checks that the zone of a subdomain can be accessed, fetching its
+    /// details so they are cached for committing its subdomains
+    pub async fn check_zone(&mut self, config: &SubdomainsConfig) -> Result<()> {
+        let timeout = config
+            .timeout
+            .or(self.config.subdomains_config.timeout)
+            .map(Duration::from_secs);
+        let api = self.api_client(config.api_token.as_deref(), timeout)?;
+        self.get_subdomain_zone(&api, config).await?;
         Ok(())
     }
+
+    /// Enabled A/AAAA record types of a subdomain, with the IP version and
+    /// detection source of each. Empty if the subdomain publishes `origins` or
+    /// a `cname` instead of detected IPs
+    fn address_records(&self, config: &SubdomainsConfig) -> Vec<(&'static str, IP, IpSource)> {
+        if config.origins.is_some() || config.cname.is_some() || config.ns.is_some() {
+            return Vec::new();
+        }
+
+        let global = &self.config.subdomains_config;
+        let a = config.a.or(global.a).unwrap_or(true);
+        let aaaa = config.aaaa.or(global.aaaa).unwrap_or(false);
+        let ipv4_source = config
+            .ipv4_source
+            .or(global.ipv4_source)
+            .unwrap_or(self.config.detection.source);
+        let ipv6_source = config
+            .ipv6_source
+            .or(global.ipv6_source)
+            .unwrap_or(self.config.detection.source);
+
+        [
+            (a, "A", IP::V4, ipv4_source),
+            (aaaa, "AAAA", IP::V6, ipv6_source),
+        ]
+        .into_iter()
+        .filter(|(use_, ..)| *use_)
+        .map(|(_, type_, ip_version, ip_source)| (type_, ip_version, ip_source))
+        .collect()
+    }
+
+    /// Sets `current`, or a new record if there is none, to `wanted`. Returns
+    /// `None` if `current` is skipped for not carrying the cf-ddns marker
+    async fn put_record(
+        &self,
+        api: &CClient,
+        zone_id: &str,
+        fqdn: &str,
+        current: Option<&dns::DnsRecord>,
+        wanted: &WantedRecord,
+        extras: &[marker::RecordExtra],
+    ) -> Result<Option<RecordOutcome>> {
+        let type_ = wanted.type_;
+        let content = content_string(&wanted.content);
+        let Some(record) = current else {
+            return self
+                .create_record(api, zone_id, fqdn, wanted)
+                .await
+                .map(Some);
+        };
+
+        let id = &record.id;
+        let old_content = content_string(&record.content);
+        let extra = extras.iter().find(|extra| extra.id == *id);
+        let old_priority = extra.and_then(|extra| extra.priority);
+        let changes = wanted.changes(&old_content, record.ttl, record.proxied, old_priority);
+        let (ttl, proxied) = wanted.ttl_proxied(record.ttl, record.proxied);
+        if changes.any() && !self.check_marker(fqdn, type_, id, extras)? {
+            return Ok(None);
+        }
+
+        let action = if !changes.any() {
+            log!(
+                self.unchanged_level(),
+                "{fqdn}: record {id} doesn't need to be modified"
+            );
+            Action::Unchanged
+        } else if self.config.dry_run {
+            let old = Some((record, old_content.as_str(), old_priority));
+            let priority = wanted.priority;
+            println!(
+                "{}",
+                format_diff(fqdn, type_, old, &content, ttl, proxied, priority)
+            );
+            Action::Updated
+        } else {
+            info!("{fqdn}: updating {type_} record with id {id}. Old content: {old_content}");
+            debug!("{fqdn}: old record: {record:?}");
+            let record = self
+                .request(
+                    api,
+                    &dns::UpdateDnsRecord {
+                        identifier: id,
+                        zone_identifier: zone_id,
+                        params: update_params(fqdn, wanted.content.clone(), ttl, proxied),
+                    },
+                )
+                .await
+                .with_context(|| format!("Failed to update {type_} record for {fqdn}"))?;
+            info!(
+                "{fqdn}: succesfully updated {type_} record with id {id}. New content: {content}"
+            );
+            debug!("{fqdn}: new record: {:?}", record.result);
+            // The update endpoint of the cloudflare crate doesn't take a
+            // priority
+            if changes.priority {
+                let params = marker::PatchRecordParams {
+                    priority: wanted.priority,
+                    ..Default::default()
+                };
+                self.patch_record(api, zone_id, fqdn, id, "priority", params)
+                    .await?;
+            }
+            Action::Updated
+        };
+
+        // Unchanged records that aren't managed by cf-ddns don't get their
+        // comment replaced either
+        if changes.any() || self.may_modify(id, extras) {
+            let current_comment = extra.and_then(|extra| extra.comment.as_deref());
+            self.sync_comment(
+                api,
+                zone_id,
+                fqdn,
+                id,
+                current_comment,
+                wanted.comment.as_deref(),
+            )
+            .await?;
+        }
+
+        Ok(Some(RecordOutcome {
+            fqdn: fqdn.to_string(),
+            type_,
+            action,
+            content,
+            old_content: Some(old_content),
+            proxied: proxied.unwrap_or(false),
+            changes,
+            id: Some(id.clone()),
+        }))
+    }
+
+    /// Creates a record for `wanted`, which doesn't exist yet
+    async fn create_record(
+        &self,
+        api: &CClient,
+        zone_id: &str,
+        fqdn: &str,
+        wanted: &WantedRecord,
+    ) -> Result<RecordOutcome> {
+        let type_ = wanted.type_;
+        let content = content_string(&wanted.content);
+        let mut outcome = RecordOutcome {
+            fqdn: fqdn.to_string(),
+            type_,
+            action: Action::Created,
+            content: content.clone(),
+            old_content: None,
+            proxied: wanted.proxied.unwrap_or(false),
+            changes: Changes::default(),
+            id: None,
+        };
+        if self.config.dry_run {
+            println!(
+                "{}",
+                format_diff(
+                    fqdn,
+                    type_,
+                    None,
+                    &content,
+                    wanted.ttl,
+                    wanted.proxied,
+                    wanted.priority
+                )
+            );
+            return Ok(outcome);
+        }
+
+        info!("{fqdn}: {type_} record not found, creating it for {content}");
+        let record = self
+            .request(
+                api,
+                &dns::CreateDnsRecord {
+                    zone_identifier: zone_id,
+                    params: create_params(
+                        fqdn,
+                        wanted.content.clone(),
+                        wanted.ttl,
+                        wanted.proxied,
+                        wanted.priority,
+                    ),
+                },
+            )
+            .await
+            .with_context(|| format!("Failed to create {type_} record for {fqdn}"))?;
+        let id = record.result.id;
+        info!("{fqdn}: successfully created {type_} record. id: {id}, content: {content}");
+        let tags = self.config.owner_tag.as_ref().map(std::slice::from_ref);
+        if wanted.comment.is_some() || tags.is_some() {
+            let params = marker::PatchRecordParams {
+                comment: wanted.comment.as_deref(),
+                tags,
+                ..Default::default()
+            };
+            self.patch_record(api, zone_id, fqdn, &id, "comment and tags", params)
+                .await?;
+        }
+        outcome.id = Some(id);
+        Ok(outcome)
+    }
+
+    /// Client, zone id and fully qualified name of the record of a subdomain
+    async fn subdomain_fqdn(
+        &mut self,
+        subdomain: &str,
+        config: &SubdomainsConfig,
+    ) -> Result<(Rc<CClient>, String, String)> {
+        let timeout = config
+            .timeout
+            .or(self.config.subdomains_config.timeout)
+            .map(Duration::from_secs);
+        let api = self.api_client(config.api_token.as_deref(), timeout)?;
+        let (zone_id, base_domain_name) = self.get_subdomain_zone(&api, config).await?;
+        debug!("Base domain name: {base_domain_name}");
+
+        let preserve_case = resolve(
+            subdomain,
+            "preserve_case",
+            config.preserve_case,
+            self.config.subdomains_config.preserve_case,
+            false,
+        );
+        let name = record_name(subdomain, preserve_case);
+        check_zone(&name, &base_domain_name, configured_zones(&self.config))
+            .with_context(|| format!("{subdomain} can't be published in zone {zone_id}"))?;
+        let fqdn = fqdn(&name, &base_domain_name);
+        debug!("fqdn: {fqdn}");
+        Ok((api, zone_id, fqdn))
+    }
+
+    /// Whether a record that is about to be modified may be, per
+    /// --require-marker. Records without the marker get it when their comment
+    /// is synced with --adopt
+    fn check_marker(
+        &self,
+        fqdn: &str,
+        type_: &str,
+        id: &str,
+        extras: &[marker::RecordExtra],
+    ) -> Result<bool> {
+        if !self.config.require_marker || has_marker(id, extras) {
+            return Ok(true);
+        }
+
+        if self.config.skip_foreign {
+            warn!("{fqdn}: {type_} record {id} doesn't carry the cf-ddns marker, skipping it");
+            return Ok(false);
+        }
+        ensure!(
+            self.config.adopt,
+            "{fqdn}: {type_} record {id} doesn't carry the cf-ddns marker, refusing to \
+            modify it. Pass --adopt to manage it"
+        );
+        info!("{fqdn}: adopting {type_} record with id {id}");
+        Ok(true)
+    }
+
+    /// Whether a record may be modified without further checks: it carries the
+    /// marker, or --require-marker isn't set, or --adopt is
+    fn may_modify(&self, id: &str, extras: &[marker::RecordExtra]) -> bool {
+        !self.config.require_marker || self.config.adopt || has_marker(id, extras)
+    }
+
+    /// Comment records should have: the configured one, with the marker
+    /// added with --require-marker
+    fn wanted_comment(&self, comment: Option<&str>) -> Option<String> {
+        match (comment, self.config.require_marker) {
+            (Some(comment), true) if !comment.contains(marker::MARKER) => {
+                Some(format!("{comment} ({})", marker::MARKER))
+            }
+            (Some(comment), _) => Some(comment.to_string()),
+            (None, true) => Some(marker::MARKER.to_string()),
+            (None, false) => None,
+        }
+    }
+
+    /// Comment of the `type_` records of a subdomain. The type-specific
+    /// comments of A/AAAA records take precedence over the generic one of the
+    /// same level
+    fn record_comment(
+        &self,
+        type_: &str,
+        config: &SubdomainsConfig,
+        global: &SubdomainsConfig,
+    ) -> Option<String> {
+        let (per_subdomain, per_global) = match type_ {
+            "A" => (&config.comment_a, &global.comment_a),
+            "AAAA" => (&config.comment_aaaa, &global.comment_aaaa),
+            _ => (&None, &None),
+        };
+        self.wanted_comment(
+            per_subdomain
+                .as_deref()
+                .or(config.comment.as_deref())
+                .or(per_global.as_deref())
+                .or(global.comment.as_deref()),
+        )
+    }
+
+    /// Sets the comment of record `id` to `wanted` if it is set and differs
+    /// from `current`
+    async fn sync_comment(
+        &self,
+        api: &CClient,
+        zone_id: &str,
+        fqdn: &str,
+        id: &str,
+        current: Option<&str>,
+        wanted: Option<&str>,
+    ) -> Result<()> {
+        let Some(wanted) = wanted else {
+            return Ok(());
+        };
+        if current == Some(wanted) {
+            return Ok(());
+        }
+        if self.config.dry_run {
+            println!("{fqdn} (comment) {id}: {current:?} -> {wanted:?}");
+            return Ok(());
+        }
+        info!("{fqdn}: setting the comment of record {id} to {wanted:?}");
+        let params = marker::PatchRecordParams {
+            comment: Some(wanted),
+            ..Default::default()
+        };
+        self.patch_record(api, zone_id, fqdn, id, "comment", params)
+            .await
+    }
+
+    /// Deletes a stale record, unless it isn't owned per --owner-tag or
+    /// --require-marker. `None` if it is kept
+    async fn delete_record(
+        &self,
+        api: &CClient,
+        zone_id: &str,
+        fqdn: &str,
+        type_: &'static str,
+        record: &dns::DnsRecord,
+        extras: &[marker::RecordExtra],
+    ) -> Result<Option<RecordOutcome>> {
+        let id = &record.id;
+        let tags = extras
+            .iter()
+            .find(|extra| extra.id == *id)
+            .map_or(&[][..], |extra| &extra.tags);
+        if !self.may_prune(fqdn, type_, id, tags) || !self.check_marker(fqdn, type_, id, extras)? {
+            return Ok(None);
+        }
+
+        let content = content_string(&record.content);
+        if self.config.dry_run {
+            println!("{fqdn} (delete) {type_}: {content}");
+        } else {
+            info!("{fqdn}: deleting {type_} record with id {id}, content: {content}");
+            self.request(
+                api,
+                &dns::DeleteDnsRecord {
+                    zone_identifier: zone_id,
+                    identifier: id,
+                },
+            )
+            .await
+            .with_context(|| format!("Failed to delete {type_} record for {fqdn}"))?;
+        }
+        Ok(Some(RecordOutcome {
+            fqdn: fqdn.to_string(),
+            type_,
+            action: Action::Deleted,
+            content: content.clone(),
+            old_content: Some(content),
+            proxied: record.proxied,
+            changes: Changes::default(),
+            id: Some(id.clone()),
+        }))
+    }
+
+    /// Whether a stale record carrying `tags` may be deleted per --owner-tag
+    fn may_prune(&self, name: &str, type_: &str, id: &str, tags: &[String]) -> bool {
+        let owned = is_owned(self.config.owner_tag.as_deref(), tags);
+        if !owned {
+            info!("{name}: leaving stale {type_} record {id}, it doesn't carry the owner tag");
+        }
+        owned
+    }
+
+    /// Comments, priorities and tags of the records named `fqdn`
+    async fn list_extras(
+        &self,
+        api: &CClient,
+        zone_id: &str,
+        fqdn: &str,
+    ) -> Result<Vec<marker::RecordExtra>> {
+        let extras = self
+            .request(
+                api,
+                &marker::ListRecordExtras {
+                    zone_identifier: zone_id,
+                    name: fqdn,
+                },
+            )
+            .await
+            .with_context(|| format!("Failed to list the record comments of {fqdn}"))?;
+        Ok(extras.result.0)
+    }
+
+    /// Sets the extra fields of record `id` that are set in `params`, which
+    /// `fields` names for errors
+    async fn patch_record(
+        &self,
+        api: &CClient,
+        zone_id: &str,
+        fqdn: &str,
+        id: &str,
+        fields: &str,
+        params: marker::PatchRecordParams<'_>,
+    ) -> Result<()> {
+        self.request(
+            api,
+            &marker::PatchRecord {
+                zone_identifier: zone_id,
+                identifier: id,
+                params,
+            },
+        )
+        .await
+        .with_context(|| format!("Failed to set the {fields} of record {id} for {fqdn}"))?;
+        Ok(())
+    }
+
+    /// PTR records of a zone named `name` and/or pointing at `content`,
+    /// following pagination
+    async fn list_ptr_records(
+        &self,
+        api: &CClient,
+        zone_id: &str,
+        name: Option<&str>,
+        content: Option<&str>,
+    ) -> Result<Vec<ptr::PtrRecord>> {
+        let mut records = Vec::new();
+        for page in 1.. {
+            let page_records = self
+                .request(
+                    api,
+                    &ptr::ListPtrRecords {
+                        zone_identifier: zone_id,
+                        name,
+                        content,
+                        page,
+                    },
+                )
+                .await
+                .with_context(|| format!("Failed to get PTR records (zone: {zone_id})"))?
+                .result
+                .0;
+
+            let last_page = page_records.len() < ptr::PER_PAGE as usize;
+            records.extend(page_records);
+            if last_page {
+                break;
+            }
+        }
+        Ok(records)
+    }
+
+    /// Points the reverse name of each detected IP at `hostname`. PTR records
+    /// of the same family pointing at it from other names are left from
+    /// previous IPs, and are deleted
+    async fn commit_ptr_records(
+        &mut self,
+        subdomain: &str,
+        config: &SubdomainsConfig,
+        hostname: &str,
+    ) -> Result<Vec<RecordOutcome>> {
+        let type_ = "PTR";
+        let hostname = hostname.trim_end_matches('.');
+        let global = self.config.clone();
+        let global = &global.subdomains_config;
+
+        let timeout = resolve_optional(subdomain, "timeout", config.timeout, global.timeout)
+            .map(Duration::from_secs);
+        let api = self.api_client(config.api_token.as_deref(), timeout)?;
+        let (zone_id, zone_name) = self.get_subdomain_zone(&api, config).await?;
+
+        // PTR records can't be proxied
+        let (configured_ttl, manage_ttl) = Ttl::managed(
+            resolve_optional(subdomain, "ttl", config.ttl, global.ttl),
+            resolve(
+                subdomain,
+                "manage_ttl",
+                config.manage_ttl,
+                global.manage_ttl,
+                true,
+            ),
+        );
+        let ttl = configured_ttl.unwrap_or_else(|| {
+            resolve_optional(
+                subdomain,
+                "default_unproxied_ttl",
+                config.default_unproxied_ttl,
+                global.default_unproxied_ttl,
+            )
+            .unwrap_or(1)
+        });
+        let create = resolve(subdomain, "create", config.create, global.create, true);
+        let source_address = resolve_optional(
+            subdomain,
+            "source_address",
+            config.source_address,
+            global.source_address,
+        );
+
+        let existing = self
+            .list_ptr_records(&api, &zone_id, None, Some(hostname))
+            .await?;
+
+        let mut outcomes = Vec::new();
+        for (_, ip_version, ip_source) in self.address_records(config) {
+            let ip: IpAddr = self
+                .get_ip(ip_version, ip_source, source_address, timeout)
+                .await?
+                .parse()?;
+            let name = ptr::reverse_name(ip);
+            ensure!(
+                name.ends_with(&format!(".{zone_name}")),
+                "{name} does not belong to zone {zone_name} (zone_id: {zone_id})"
+            );
+            let family_suffix = match ip_version {
+                IP::V4 => ".in-addr.arpa",
+                IP::V6 => ".ip6.arpa",
+            };
+
+            for record in &existing {
+                let stale = record.name.ends_with(family_suffix)
+                    && !record.name.eq_ignore_ascii_case(&name);
+                if !stale || !self.may_prune(&record.name, type_, &record.id, &record.tags) {
+                    continue;
+                }
+                if self.config.dry_run {
+                    println!("{} (delete) {type_}: {hostname}", record.name);
+                } else {
+                    info!(
+                        "{}: deleting stale {type_} record {}",
+                        record.name, record.id
+                    );
+                    self.request(
+                        &api,
+                        &dns::DeleteDnsRecord {
+                            zone_identifier: &zone_id,
+                            identifier: &record.id,
+                        },
+                    )
+                    .await
+                    .with_context(|| format!("Failed to delete {type_} record {}", record.name))?;
+                }
+                outcomes.push(RecordOutcome {
+                    fqdn: record.name.clone(),
+                    type_,
+                    action: Action::Deleted,
+                    content: hostname.to_string(),
+                    old_content: Some(hostname.to_string()),
+                    proxied: false,
+                    changes: Changes::default(),
+                    id: Some(record.id.clone()),
+                });
+            }
+
+            // The reverse name may point at another hostname, which isn't
+            // listed with the records pointing at this one
+            let current = self
+                .list_ptr_records(&api, &zone_id, Some(&name), None)
+                .await?
+                .into_iter()
+                .next();
+            let changes = Changes {
+                content: current
+                    .as_ref()
+                    .is_some_and(|record| !record.content.eq_ignore_ascii_case(hostname)),
+                ttl: current
+                    .as_ref()
+                    .is_some_and(|record| manage_ttl && record.ttl != ttl),
+                ..Default::default()
+            };
+            let ttl = match &current {
+                Some(record) if !manage_ttl => record.ttl,
+                _ => ttl,
+            };
+            let mut id = current.as_ref().map(|record| record.id.clone());
+            let action = match &current {
+                Some(record) if !changes.any() => {
+                    log!(
+                        self.unchanged_level(),
+                        "{name}: record {} doesn't need to be modified",
+                        record.id
+                    );
+                    Action::Unchanged
+                }
+                Some(record) => {
+                    if self.config.dry_run {
+                        let mut diff = if changes.content {
+                            format!("{name} {type_}: {} -> {hostname}", record.content)
+                        } else {
+                            format!("{name} {type_}: {hostname}")
+                        };
+                        if changes.ttl {
+                            diff += &format!(" (ttl {}->{ttl})", record.ttl);
+                        }
+                        println!("{diff}");
+                    } else {
+                        info!("{name}: updating {type_} record with id {}", record.id);
+                        self.request(
+                            &api,
+                            &ptr::UpdatePtrRecord {
+                                zone_identifier: &zone_id,
+                                identifier: &record.id,
+                                params: ptr::PtrRecordParams::new(
+                                    &name,
+                                    hostname,
+                                    ttl,
+                                    &record.tags,
+                                ),
+                            },
+                        )
+                        .await
+                        .with_context(|| format!("Failed to update {type_} record for {name}"))?;
+                    }
+                    Action::Updated
+                }
+                None => {
+                    if !create {
+                        bail!("{name}: {type_} record not found and record creation is disabled");
+                    }
+                    if self.config.dry_run {
+                        println!("{name} (create) {type_}: {hostname}");
+                    } else {
+                        info!("{name}: creating {type_} record for {hostname}");
+                        let record = self
+                            .request(
+                                &api,
+                                &ptr::CreatePtrRecord {
+                                    zone_identifier: &zone_id,
+                                    params: ptr::PtrRecordParams::new(
+                                        &name,
+                                        hostname,
+                                        ttl,
+                                        self.config.owner_tag.as_slice(),
+                                    ),
+                                },
+                            )
+                            .await
+                            .with_context(|| {
+                                format!("Failed to create {type_} record for {name}")
+                            })?;
+                        info!(
+                            "{name}: successfully created {type_} record. id: {}",
+                            record.result.id
+                        );
+                        id = Some(record.result.id);
+                    }
+                    Action::Created
+                }
+            };
+            outcomes.push(RecordOutcome {
+                fqdn: name,
+                type_,
+                action,
+                content: hostname.to_string(),
+                old_content: current.map(|record| record.content),
+                proxied: false,
+                changes,
+                id,
+            });
+        }
+        Ok(outcomes)
+    }
+
+    /// Current records of every subdomain, one per line or as JSON if `raw`
+    pub async fn status(&mut self, raw: bool) -> Result<String> {
+        let global = self.config.clone();
+        let mut subdomains: Vec<_> = global.subdomains.iter().collect();
+        subdomains.sort_by_key(|(subdomain, _)| *subdomain);
+
+        let mut lines = String::new();
+        let mut json_records = Vec::new();
+        for (subdomain, config) in subdomains {
+            for (_, config) in config.per_zone() {
+                let (api, zone_id, fqdn) = self.subdomain_fqdn(subdomain, &config).await?;
+                let records = self.get_dns_records(&api, &zone_id, &fqdn).await?;
+                if records.is_empty() {
+                    lines += &format!("{fqdn}: no records\n");
+                }
+
+                for record in records {
+                    // DnsRecord isn't Serialize, but its content is
+                    let mut json = serde_json::to_value(&record.content)?;
+                    if raw {
+                        json["id"] = record.id.into();
+                        json["name"] = record.name.into();
+                        json["ttl"] = record.ttl.into();
+                        json["proxied"] = record.proxied.into();
+                        json["proxiable"] = record.proxiable.into();
+                        json["locked"] = record.locked.into();
+                        json["zone_id"] = record.zone_id.into();
+                        json["zone_name"] = record.zone_name.into();
+                        json["created_on"] = record.created_on.to_rfc3339().into();
+                        json["modified_on"] = record.modified_on.to_rfc3339().into();
+                        json["meta"] = serde_json::json!({ "auto_added": record.meta.auto_added });
+                        json_records.push(json);
+                    } else {
+                        lines += &format!(
+                            "{} {} {} (id {}, ttl {}, proxied {})\n",
+                            record.name,
+                            json["type"].as_str().unwrap_or_default(),
+                            json["content"].as_str().unwrap_or_default(),
+                            record.id,
+                            record.ttl,
+                            record.proxied,
+                        );
+                    }
+                }
+            }
+        }
+
+        if raw {
+            Ok(serde_json::to_string_pretty(&json_records)? + "\n")
+        } else {
+            Ok(lines)
+        }
+    }
+
+    /// Record name and zone id of every configured subdomain as JSON, an
+    /// entry per zone for subdomains committed to several
+    pub async fn fqdns(&mut self) -> Result<String> {
+        #[derive(Serialize)]
+        struct Effective {
+            fqdn: String,
+            zone_id: String,
+        }
+
+        let global = self.config.clone();
+        let mut fqdns: BTreeMap<&str, Vec<Effective>> = BTreeMap::new();
+        for (subdomain, config) in &global.subdomains {
+            for (_, config) in config.per_zone() {
+                let (_, zone_id, fqdn) = self.subdomain_fqdn(subdomain, &config).await?;
+                fqdns
+                    .entry(subdomain)
+                    .or_default()
+                    .push(Effective { fqdn, zone_id });
+            }
+        }
+        Ok(serde_json::to_string_pretty(&fqdns)? + "\n")
+    }
+
+    /// Current records of every configured subdomain
+    pub async fn snapshot(&mut self) -> Result<Snapshot> {
+        let global = self.config.clone();
+        let mut records = Vec::new();
+        for (subdomain, config) in &global.subdomains {
+            for (_, config) in config.per_zone() {
+                let (api, zone_id, fqdn) = self.subdomain_fqdn(subdomain, &config).await?;
+                for record in self.get_dns_records(&api, &zone_id, &fqdn).await? {
+                    records.push(SnapshotRecord::of(&record)?);
+                }
+            }
+        }
+        // Subdomains may share a name
+        records.sort_by(|a, b| (&a.name, &a.type_, &a.id).cmp(&(&b.name, &b.type_, &b.id)));
+        records.dedup_by(|a, b| a.id == b.id);
+
+        Ok(Snapshot {
+            taken_on: chrono::Utc::now().to_rfc3339(),
+            records,
+        })
+    }
+
+    pub async fn commit_record(
+        &mut self,
+        subdomain: &str,
+        config: &SubdomainsConfig,
+    ) -> Result<Vec<RecordOutcome>> {
+        const RETRY_DELAY: Duration = Duration::from_secs(2);
+
+        let retries = resolve(
+            subdomain,
+            "retries",
+            config.retries,
+            self.config.subdomains_config.retries,
+            0,
+        );
+        let mut attempt = 0;
+        loop {
+            match self.try_commit_record(subdomain, config).await {
+                Err(e) if attempt < retries => {
+                    attempt += 1;
+                    warn!(
+                        "Failed to commit subdomain {subdomain:?}, retrying \
+                        ({attempt}/{retries}): {e:#}"
+                    );
+                    // Detection failures are remembered for the rest of the
+                    // run, which would make the retry fail right away
+                    self.ip_failures.clear();
+                    tokio::time::sleep(RETRY_DELAY).await;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Commits the records of the subdomain once, reverting them on failure
+    /// if it's atomic
+    async fn try_commit_record(
+        &mut self,
+        subdomain: &str,
+        config: &SubdomainsConfig,
+    ) -> Result<Vec<RecordOutcome>> {
+        let atomic = resolve(
+            subdomain,
+            "atomic",
+            config.atomic,
+            self.config.subdomains_config.atomic,
+            false,
+        );
+        if !atomic || self.config.dry_run {
+            return self.commit_records(subdomain, config).await;
+        }
+
+        let (api, zone_id, fqdn) = self.subdomain_fqdn(subdomain, config).await?;
+        let snapshot = self.get_dns_records(&api, &zone_id, &fqdn).await?;
+        let e = match self.commit_records(subdomain, config).await {
+            Ok(outcomes) => return Ok(outcomes),
+            Err(e) => e,
+        };
+        warn!("Failed to commit {fqdn}, reverting the records updated in this run");
+        if let Err(restore_error) = self.restore_records(&api, &zone_id, &fqdn, &snapshot).await {
+            error!("Failed to revert records of {fqdn}: {restore_error:?}");
+        }
+        Err(e)
+    }
+
+    /// Puts the records managed by cf-ddns back to `snapshot`: records created
+    /// since are deleted, changed ones updated and deleted ones created again
+    async fn restore_records(
+        &self,
+        api: &CClient,
+        zone_id: &str,
+        fqdn: &str,
+        snapshot: &[dns::DnsRecord],
+    ) -> Result<()> {
+        fn is_managed(record: &dns::DnsRecord) -> bool {
+            matches!(
+                record.content,
+                dns::DnsContent::A { .. }
+                    | dns::DnsContent::AAAA { .. }
+                    | dns::DnsContent::CNAME { .. }
+                    | dns::DnsContent::NS { .. }
+            )
+        }
+
+        let current = self.get_dns_records(api, zone_id, fqdn).await?;
+        for record in current.iter().filter(|record| is_managed(record)) {
+            let Some(old) = snapshot.iter().find(|old| old.id == record.id) else {
+                info!("Reverting: deleting {} ({})", record.name, record.id);
+                self.request(
+                    api,
+                    &dns::DeleteDnsRecord {
+                        zone_identifier: zone_id,
+                        identifier: &record.id,
+                    },
+                )
+                .await
+                .with_context(|| format!("Failed to delete record {}", record.id))?;
+                continue;
+            };
+            let unchanged = old.ttl == record.ttl
+                && old.proxied == record.proxied
+                && serde_json::to_value(&old.content)? == serde_json::to_value(&record.content)?;
+            if unchanged {
+                continue;
+            }
+            info!("Reverting: restoring {} ({})", old.name, old.id);
+            self.request(
+                api,
+                &dns::UpdateDnsRecord {
+                    zone_identifier: zone_id,
+                    identifier: &old.id,
+                    params: dns::UpdateDnsRecordParams {
+                        ttl: Some(old.ttl),
+                        proxied: old.proxiable.then_some(old.proxied),
+                        name: &old.name,
+                        content: old.content.clone(),
+                    },
+                },
+            )
+            .await
+            .with_context(|| format!("Failed to restore record {}", old.id))?;
+        }
+
+        for old in snapshot.iter().filter(|old| is_managed(old)) {
+            if current.iter().any(|record| record.id == old.id) {
+                continue;
+            }
+            info!("Reverting: recreating {} ({})", old.name, old.id);
+            self.request(
+                api,
+                &dns::CreateDnsRecord {
+                    zone_identifier: zone_id,
+                    params: dns::CreateDnsRecordParams {
+                        content: old.content.clone(),
+                        name: &old.name,
+                        proxied: old.proxiable.then_some(old.proxied),
+                        ttl: Some(old.ttl),
+                        priority: None,
+                    },
+                },
+            )
+            .await
+            .with_context(|| format!("Failed to recreate record {}", old.id))?;
+        }
+        Ok(())
+    }
+
+    async fn commit_records(
+        &mut self,
+        subdomain: &str,
+        config: &SubdomainsConfig,
+    ) -> Result<Vec<RecordOutcome>> {
+        debug!("[commit_record] subdomain: {subdomain}");
+        if let Some(hostname) = &config.ptr {
+            return self.commit_ptr_records(subdomain, config, hostname).await;
+        }
+        let (api, zone_id, fqdn) = self.subdomain_fqdn(subdomain, config).await?;
+        let global = self.config.clone();
+        let global = &global.subdomains_config;
+
+        let mut address_records = self.address_records(config);
+        if address_records.is_empty()
+            && config.ns.is_none()
+            && config.origins.is_none()
+            && config.cname.is_none()
+        {
+            warn!("A = false and AAAA = false for subdomain {subdomain}");
+            return Ok(Vec::new());
+        }
+
+        let dns_records = match &config.record_id {
+            Some(record_id) => {
+                ensure!(
+                    config.ns.is_none() && config.origins.is_none() && config.cname.is_none(),
+                    "{fqdn}: record_id can't be combined with ns, origins or cname"
+                );
+                let record = self
+                    .request(
+                        &api,
+                        &marker::GetDnsRecord {
+                            zone_identifier: &zone_id,
+                            identifier: record_id,
+                        },
+                    )
+                    .await
+                    .with_context(|| format!("{fqdn}: failed to get record {record_id}"))?
+                    .result;
+                ensure!(
+                    record.name.eq_ignore_ascii_case(&fqdn),
+                    "{fqdn}: record {record_id} belongs to {}",
+                    record.name
+                );
+                let (type_, ip_version) = match record.content {
+                    dns::DnsContent::A { .. } => ("A", IP::V4),
+                    dns::DnsContent::AAAA { .. } => ("AAAA", IP::V6),
+                    _ => bail!("{fqdn}: record {record_id} is not an A or AAAA record"),
+                };
+                // Only the pinned record is updated, no other record is created
+                address_records.retain(|(_, version, _)| *version == ip_version);
+                ensure!(
+                    !address_records.is_empty(),
+                    "{fqdn}: record {record_id} is an {type_} record but {type_} is disabled"
+                );
+                vec![record]
+            }
+            None => self.get_dns_records(&api, &zone_id, &fqdn).await?,
+        };
+
+        let now = chrono::Local::now().time();
+        let scheduled_proxied = |config: &SubdomainsConfig| {
+            config
+                .proxied_schedule
+                .as_deref()
+                .map(|window| in_time_window(window, now))
+                .transpose()
+        };
+        let schedules = (scheduled_proxied(config)?, scheduled_proxied(global)?);
+        let proxied_for = |ip_version| {
+            resolve_proxied(
+                subdomain,
+                ip_version,
+                config,
+                global,
+                self.config.proxied,
+                schedules,
+            )
+        };
+        let proxied = proxied_for(None);
+        let proxied_a = proxied_for(Some(IP::V4));
+        let proxied_aaaa = proxied_for(Some(IP::V6));
+        let proxied_of = |ip_version| match ip_version {
+            IP::V4 => proxied_a,
+            IP::V6 => proxied_aaaa,
+        };
+
+        let aaaa_only = address_records
+            .iter()
+            .all(|(_, ip_version, _)| *ip_version == IP::V6);
+        if proxied_aaaa && aaaa_only && !address_records.is_empty() {
+            warn!(
+                "{fqdn}: proxying an AAAA record without an A record, Cloudflare will only \
+                reach the origin over IPv6. Make sure it has working IPv6 connectivity"
+            );
+        }
+
+        let default_unproxied_ttl = resolve_optional(
+            subdomain,
+            "default_unproxied_ttl",
+            config.default_unproxied_ttl,
+            global.default_unproxied_ttl,
+        );
+        let (configured_ttl, manage_ttl) = Ttl::managed(
+            resolve_optional(subdomain, "ttl", config.ttl, global.ttl),
+            resolve(
+                subdomain,
+                "manage_ttl",
+                config.manage_ttl,
+                global.manage_ttl,
+                true,
+            ),
+        );
+        let ttl_of = |proxied: bool| {
+            configured_ttl
+                .or(default_unproxied_ttl.filter(|_| !proxied))
+                .unwrap_or(1)
+        };
+        let ttl = ttl_of(proxied);
+
+        // Neither UpdateDnsRecordParams nor DnsRecord expose a priority outside
+        // of the MX content, so it is listed and set with the comments
+        let priority = resolve_optional(subdomain, "priority", config.priority, global.priority);
+
+        let manage_proxied = resolve(
+            subdomain,
+            "manage_proxied",
+            config.manage_proxied,
+            global.manage_proxied,
+            true,
+        );
+
+        let create = resolve(subdomain, "create", config.create, global.create, true);
+
+        let source_address = resolve_optional(
+            subdomain,
+            "source_address",
+            config.source_address,
+            global.source_address,
+        );
+
+        let ipv6_network_bits = resolve_optional(
+            subdomain,
+            "ipv6_network_bits",
+            config.ipv6_network_bits,
+            global.ipv6_network_bits,
+        );
+        let ipv6_compare_bits = resolve_optional(
+            subdomain,
+            "ipv6_compare_bits",
+            config.ipv6_compare_bits,
+            global.ipv6_compare_bits,
+        );
+        let timeout = resolve_optional(subdomain, "timeout", config.timeout, global.timeout)
+            .map(Duration::from_secs);
+        // --strict-single takes precedence over the subdomain's setting too
+        let multiple_records = if self.config.strict_single {
+            MultipleRecords::Error
+        } else {
+            resolve(
+                subdomain,
+                "multiple_records",
+                config.multiple_records,
+                global.multiple_records,
+                MultipleRecords::First,
+            )
+        };
+
+        let comment_a = self.record_comment("A", config, global);
+        let comment_aaaa = self.record_comment("AAAA", config, global);
+        // Comments are needed to check the marker of every type of record, and
+        // set on all of them, like the priority on the A/AAAA ones
+        // Tags are needed to prune origins
+        let list_extras = comment_a.is_some()
+            || comment_aaaa.is_some()
+            || priority.is_some()
+            || self.config.owner_tag.is_some();
+        let extras = if list_extras {
+            self.list_extras(&api, &zone_id, &fqdn).await?
+        } else {
+            Vec::new()
+        };
+        let address_record = |type_, ip: IpAddr| {
+            let proxied = proxied_of(match ip {
+                IpAddr::V4(_) => IP::V4,
+                IpAddr::V6(_) => IP::V6,
+            });
+            WantedRecord {
+                type_,
+                content: address_content(ip),
+                ttl: ttl_of(proxied),
+                proxied: Some(proxied),
+                manage_ttl,
+                manage_proxied,
+                priority,
+                comment: match ip {
+                    IpAddr::V4(_) => comment_a.clone(),
+                    IpAddr::V6(_) => comment_aaaa.clone(),
+                },
+            }
+        };
+
+        let mut outcomes = Vec::new();
+        for (type_, ip_version, ip_source) in address_records {
+            let ip = self
+                .get_ip(ip_version, ip_source, source_address, timeout)
+                .await?;
+            if let (true, Ok(IpAddr::V4(v4))) = (self.config.prefer_ipv6, ip.parse()) {
+                if is_cgnat(v4) {
+                    warn!(
+                        "{fqdn}: {ip} is behind carrier-grade NAT, not updating the A record \
+                        (--prefer-ipv6)"
+                    );
+                    continue;
+                }
+            }
+            let ip = match (ip_version, ipv6_network_bits) {
+                (IP::V6, Some(bits)) => ipv6_network(ip.parse()?, bits).to_string(),
+                _ => ip,
+            };
+
+            let existing: Vec<(&dns::DnsRecord, String)> = dns_records
+                .iter()
+                .filter_map(|record| match (ip_version, &record.content) {
+                    (IP::V4, dns::DnsContent::A { content }) => Some((record, content.to_string())),
+                    (IP::V6, dns::DnsContent::AAAA { content }) => {
+                        Some((record, content.to_string()))
+                    }
+                    _ => None,
+                })
+                .collect();
+            let existing = match (existing.len(), multiple_records) {
+                (0 | 1, _) | (_, MultipleRecords::All) => existing,
+                (n, MultipleRecords::Error) => {
+                    bail!("{fqdn}: found {n} {type_} records, expected at most one")
+                }
+                (n, MultipleRecords::First) => {
+                    warn!(
+                        "{fqdn}: found {n} {type_} records, only updating the first one. \
+                        Set multiple_records to \"all\" or \"error\" to change this"
+                    );
+                    existing.into_iter().take(1).collect()
+                }
+            };
+
+            if !existing.is_empty() {
+                for (record, record_ip) in existing {
+                    // The record keeps its interface id as long as the prefix is
+                    // the same
+                    let ip = match (ip_version, ipv6_compare_bits) {
+                        (IP::V6, Some(bits))
+                            if ipv6_network(ip.parse()?, bits)
+                                == ipv6_network(record_ip.parse()?, bits) =>
+                        {
+                            debug!("{fqdn}: {ip} has the same /{bits} prefix as {record_ip}");
+                            record_ip.clone()
+                        }
+                        _ => ip.clone(),
+                    };
+                    let wanted = address_record(type_, ip.parse()?);
+                    let outcome = self
+                        .put_record(&api, &zone_id, &fqdn, Some(record), &wanted, &extras)
+                        .await?;
+                    outcomes.extend(outcome);
+                }
+            } else {
+                if !create {
+                    bail!("{fqdn}: {type_} record not found and record creation is disabled");
+                }
+                if self.config.recheck_before_create && !self.config.dry_run {
+                    // Another process may have created the record since it was listed
+                    let records = self.get_dns_records(&api, &zone_id, &fqdn).await?;
+                    let exists = records.iter().any(|record| {
+                        matches!(
+                            (ip_version, &record.content),
+                            (IP::V4, dns::DnsContent::A { .. })
+                                | (IP::V6, dns::DnsContent::AAAA { .. })
+                        )
+                    });
+                    if exists {
+                        warn!(
+                            "{fqdn}: {type_} record was created in the meantime, not creating \
+                            it again"
+                        );
+                        continue;
+                    }
+                }
+
+                let wanted = address_record(type_, ip.parse()?);
+                let e = match self
+                    .put_record(&api, &zone_id, &fqdn, None, &wanted, &extras)
+                    .await
+                {
+                    Ok(outcome) => {
+                        outcomes.extend(outcome);
+                        continue;
+                    }
+                    Err(e) => e,
+                };
+                let conflict = e
+                    .chain()
+                    .filter_map(|e| e.downcast_ref::<ApiFailure>())
+                    .any(may_already_exist);
+                if !conflict {
+                    return Err(e);
+                }
+                // The records are listed again to tell whether another tool
+                // created it since, and it goes through the same checks as if
+                // it had been listed in the first place
+                let records = self.get_dns_records(&api, &zone_id, &fqdn).await?;
+                let extras = if list_extras {
+                    self.list_extras(&api, &zone_id, &fqdn).await?
+                } else {
+                    Vec::new()
+                };
+                let current = records.iter().find(|record| {
+                    matches!(
+                        (ip_version, &record.content),
+                        (IP::V4, dns::DnsContent::A { .. })
+                            | (IP::V6, dns::DnsContent::AAAA { .. })
+                    )
+                });
+                let Some(current) = current else {
+                    return Err(e);
+                };
+                warn!("{fqdn}: {type_} record was created in the meantime, updating it instead");
+                let outcome = self
+                    .put_record(&api, &zone_id, &fqdn, Some(current), &wanted, &extras)
+                    .await?;
+                outcomes.extend(outcome);
+            }
+        }
+
+        if let Some(origins) = &config.origins {
+            let timeout = Duration::from_secs(
+                config
+                    .health_check_timeout
+                    .or(self.config.subdomains_config.health_check_timeout)
+                    .unwrap_or(5),
+            );
+            let results = futures::future::join_all(origins.iter().map(|origin| async move {
+                match &origin.health_check {
+                    Some(check) => health_check(origin.ip, check, timeout).await,
+                    None => Ok(()),
+                }
+            }))
+            .await;
+
+            let mut healthy = Vec::new();
+            for (origin, result) in origins.iter().zip(results) {
+                match result {
+                    Ok(()) => healthy.push(origin.ip),
+                    Err(e) => warn!("{fqdn}: origin {} is unhealthy: {e:#}", origin.ip),
+                }
+            }
+
+            for type_ in ["A", "AAAA"] {
+                let of_type = |ip: &IpAddr| ip.is_ipv4() == (type_ == "A");
+                if !origins.iter().any(|origin| of_type(&origin.ip)) {
+                    continue;
+                }
+                let desired: Vec<IpAddr> = healthy.iter().copied().filter(of_type).collect();
+                if desired.is_empty() {
+                    warn!("{fqdn}: no {type_} origin is healthy, leaving the records as they are");
+                    continue;
+                }
+
+                let existing: Vec<(&dns::DnsRecord, IpAddr)> = dns_records
+                    .iter()
+                    .filter_map(|record| match record.content {
+                        dns::DnsContent::A { content } => Some((record, IpAddr::V4(content))),
+                        dns::DnsContent::AAAA { content } => Some((record, IpAddr::V6(content))),
+                        _ => None,
+                    })
+                    .filter(|(_, ip)| of_type(ip))
+                    .collect();
+                // Records of origins that are no longer healthy are reused for
+                // the healthy ones that aren't published yet, the rest deleted
+                let mut stale = existing.iter().filter(|(_, ip)| !desired.contains(ip));
+
+                for ip in &desired {
+                    let current = existing
+                        .iter()
+                        .find(|(_, record_ip)| record_ip == ip)
+                        .or_else(|| stale.next())
+                        .map(|(record, _)| *record);
+                    if current.is_none() && !create {
+                        bail!(
+                            "{fqdn}: {type_} record for {ip} not found and record creation is \
+                            disabled"
+                        );
+                    }
+                    let wanted = address_record(type_, *ip);
+                    let outcome = self
+                        .put_record(&api, &zone_id, &fqdn, current, &wanted, &extras)
+                        .await?;
+                    outcomes.extend(outcome);
+                }
+
+                for (record, _) in stale {
+                    let outcome = self
+                        .delete_record(&api, &zone_id, &fqdn, type_, record, &extras)
+                        .await?;
+                    outcomes.extend(outcome);
+                }
+            }
+        }
+
+        if let Some(target) = &config.cname {
+            let type_ = "CNAME";
+            let transforms = config.transform.as_ref().or(global.transform.as_ref());
+            let target = transforms
+                .into_iter()
+                .flatten()
+                .fold(target.clone(), |target, transform| transform.apply(&target));
+            let target = target.trim_end_matches('.');
+            let current = dns_records
+                .iter()
+                .find(|record| matches!(record.content, dns::DnsContent::CNAME { .. }));
+            if current.is_none() && !create {
+                bail!("{fqdn}: {type_} record not found and record creation is disabled");
+            }
+            let wanted = WantedRecord {
+                type_,
+                content: dns::DnsContent::CNAME {
+                    content: target.to_string(),
+                },
+                ttl,
+                proxied: Some(proxied),
+                manage_ttl,
+                manage_proxied,
+                priority: None,
+                comment: self.record_comment(type_, config, global),
+            };
+            let outcome = self
+                .put_record(&api, &zone_id, &fqdn, current, &wanted, &extras)
+                .await?;
+            outcomes.extend(outcome);
+        }
+
+        if let Some(hostnames) = &config.ns {
+            let type_ = "NS";
+            let existing: Vec<(&dns::DnsRecord, &str)> = dns_records
+                .iter()
+                .filter_map(|record| match &record.content {
+                    dns::DnsContent::NS { content } => Some((record, content.as_str())),
+                    _ => None,
+                })
+                .collect();
+            let is_configured = |content: &str| {
+                hostnames
+                    .iter()
+                    .any(|hostname| hostname.trim_end_matches('.').eq_ignore_ascii_case(content))
+            };
+            // NS records pointing to hostnames that are no longer configured are
+            // reused for the missing ones instead of creating new records, the
+            // rest deleted
+            let mut stale = existing
+                .iter()
+                .filter(|(_, content)| !is_configured(content));
+
+            for hostname in hostnames {
+                let hostname = hostname.trim_end_matches('.');
+                let current = existing
+                    .iter()
+                    .find(|(_, content)| content.eq_ignore_ascii_case(hostname))
+                    .or_else(|| stale.next())
+                    .map(|(record, _)| *record);
+                if current.is_none() && !create {
+                    bail!(
+                        "{fqdn}: {type_} record for {hostname} not found and record creation \
+                        is disabled"
+                    );
+                }
+                // NS records can't be proxied
+                let wanted = WantedRecord {
+                    type_,
+                    content: dns::DnsContent::NS {
+                        content: hostname.to_string(),
+                    },
+                    ttl,
+                    proxied: None,
+                    manage_ttl,
+                    manage_proxied,
+                    priority: None,
+                    comment: self.record_comment(type_, config, global),
+                };
+                let outcome = self
+                    .put_record(&api, &zone_id, &fqdn, current, &wanted, &extras)
+                    .await?;
+                outcomes.extend(outcome);
+            }
+
+            for (record, _) in stale {
+                let outcome = self
+                    .delete_record(&api, &zone_id, &fqdn, type_, record, &extras)
+                    .await?;
+                outcomes.extend(outcome);
+            }
+        }
+
+        Ok(outcomes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use cloudflare::framework::response::{ApiError, ApiErrors};
+    use futures::future::{FutureExt, LocalBoxFuture};
+
+    use super::*;
+
+    fn cname(target: &str, proxied: bool) -> WantedRecord {
+        WantedRecord {
+            type_: "CNAME",
+            content: dns::DnsContent::CNAME {
+                content: target.to_string(),
+            },
+            ttl: 1,
+            proxied: Some(proxied),
+            manage_ttl: true,
+            manage_proxied: true,
+            priority: None,
+            comment: None,
+        }
+    }
+
+    /// Config of a run with `args` and the config file `name` holding
+    /// `contents`
+    async fn new_config(name: &str, contents: &str, args: &[&str]) -> Config {
+        let path = std::env::temp_dir().join(name);
+        std::fs::write(&path, contents).unwrap();
+        let base = [
+            "cf-ddns",
+            "--config",
+            path.to_str().unwrap(),
+            "--api-token",
+            "token",
+        ];
+        Config::new(Args::parse_from(base.iter().chain(args)))
+            .await
+            .unwrap()
+    }
+
+    /// Record as the API returns it
+    fn record(content: &str, ttl: u32, proxied: bool) -> dns::DnsRecord {
+        serde_json::from_value(serde_json::json!({
+            "id": "372e67954025e0ba6aaa6d586b9e0b59",
+            "zone_id": "023e105f4ecef8ad9ca31a8372d0c353",
+            "zone_name": "example.com",
+            "name": "home.example.com",
+            "type": "A",
+            "content": content,
+            "proxiable": true,
+            "proxied": proxied,
+            "ttl": ttl,
+            "locked": false,
+            "meta": { "auto_added": false, "source": "primary" },
+            "comment": null,
+            "tags": [],
+            "created_on": "2024-01-01T05:20:00.12345Z",
+            "modified_on": "2024-01-01T05:20:00.12345Z"
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn dry_run_diffs() {
+        let fqdn = "home.example.com";
+        let old = record("1.2.3.4", 1, true);
+        let diff = |content, ttl, proxied, priority| {
+            format_diff(
+                fqdn,
+                "A",
+                Some((&old, "1.2.3.4", None)),
+                content,
+                ttl,
+                proxied,
+                priority,
+            )
+        };
+        assert_eq!(
+            diff("5.6.7.8", 1, Some(true), None),
+            "home.example.com A: 1.2.3.4 -> 5.6.7.8"
+        );
+        assert_eq!(
+            diff("1.2.3.4", 300, Some(false), None),
+            "home.example.com A: 1.2.3.4 (ttl 1->300, proxied true->false)"
+        );
+        assert_eq!(
+            diff("1.2.3.4", 1, None, Some(10)),
+            "home.example.com A: 1.2.3.4 (priority none->10)"
+        );
+        assert_eq!(
+            format_diff(fqdn, "A", None, "5.6.7.8", 1, Some(true), None),
+            "home.example.com (create) A: 5.6.7.8"
+        );
+    }
+
+    #[test]
+    fn fqdns() {
+        for (name, fqdn_) in [
+            ("home", "home.example.com"),
+            ("a.b", "a.b.example.com"),
+            ("home.example.com", "home.example.com"),
+            ("home.example.com.", "home.example.com"),
+            ("@", "example.com"),
+            ("", "example.com"),
+            // Only whole labels match the base domain
+            ("myexample.com", "myexample.com.example.com"),
+            ("home.example.com.au", "home.example.com.au.example.com"),
+        ] {
+            assert_eq!(fqdn(name, "example.com"), fqdn_, "{name:?}");
+        }
+    }
+
+    #[test]
+    fn mixed_case_fqdns() {
+        assert_eq!(fqdn("Home", "example.com"), "Home.example.com");
+        assert_eq!(fqdn("A.B", "example.com"), "A.B.example.com");
+        assert_eq!(fqdn("Home.Example.COM", "example.com"), "Home.example.com");
+        assert_eq!(fqdn("EXAMPLE.com", "example.com"), "example.com");
+    }
+
+    #[test]
+    fn unchanged_proxied_cname() {
+        let wanted = cname("target.example.net", true);
+        assert!(!wanted.changes("target.example.net", 1, true, None).any());
+        assert!(!wanted.changes("Target.Example.NET", 1, true, None).any());
+    }
+
+    #[test]
+    fn cname_proxied_flip() {
+        let changes =
+            cname("target.example.net", false).changes("target.example.net", 1, true, None);
+        assert_eq!(
+            changes,
+            Changes {
+                proxied: true,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            cname("target.example.net", false).ttl_proxied(1, true),
+            (1, Some(false))
+        );
+    }
+
+    fn api_error(status: StatusCode, messages: &[&str]) -> ApiFailure {
+        let errors = messages
+            .iter()
+            .map(|message| ApiError {
+                code: 1004,
+                message: message.to_string(),
+                other: Default::default(),
+            })
+            .collect();
+        ApiFailure::Error(
+            status,
+            ApiErrors {
+                errors,
+                other: Default::default(),
+            },
+        )
+    }
+
+    #[test]
+    fn create_conflicts() {
+        // The errors of the "already exists" codes can't be parsed
+        assert!(may_already_exist(&api_error(StatusCode::BAD_REQUEST, &[])));
+        assert!(may_already_exist(&api_error(
+            StatusCode::BAD_REQUEST,
+            &["An A, AAAA, or CNAME record with that host already exists."]
+        )));
+    }
+
+    #[test]
+    fn create_failures_that_arent_conflicts() {
+        assert!(!may_already_exist(&api_error(
+            StatusCode::BAD_REQUEST,
+            &["DNS Validation Error"]
+        )));
+        assert!(!may_already_exist(&api_error(StatusCode::FORBIDDEN, &[])));
+        assert!(!may_already_exist(&api_error(
+            StatusCode::UNAUTHORIZED,
+            &["Invalid API Token"]
+        )));
+    }
+
+    #[test]
+    fn forbidden_zone_reads() {
+        let forbidden = Report::new(api_error(StatusCode::FORBIDDEN, &[]))
+            .wrap_err("Failed to get zone details (zone: 1234)");
+        assert!(is_forbidden(&forbidden));
+        let not_found = Report::new(api_error(StatusCode::NOT_FOUND, &["Invalid zone"]));
+        assert!(!is_forbidden(&not_found));
+        assert!(!is_forbidden(&eyre!("Connection refused")));
+    }
+
+    #[test]
+    fn unmanaged_proxied_is_kept() {
+        let wanted = WantedRecord {
+            manage_proxied: false,
+            ..cname("target.example.net", false)
+        };
+        assert!(!wanted.changes("target.example.net", 1, true, None).any());
+        assert_eq!(wanted.ttl_proxied(1, true), (1, Some(true)));
+    }
+
+    #[test]
+    fn preserve_case() {
+        assert_eq!(
+            record_name("_Acme-Challenge.Home", false),
+            "_acme-challenge.home"
+        );
+        assert_eq!(
+            record_name(" _Acme-Challenge.Home ", true),
+            "_Acme-Challenge.Home"
+        );
+        // Only the base domain's part of a fully qualified name is lowercased
+        let name = record_name("_Acme-Challenge.Home.Example.COM", true);
+        assert_eq!(
+            fqdn(&name, "example.com"),
+            "_Acme-Challenge.Home.example.com"
+        );
+        assert_eq!(
+            fqdn(&record_name("_Acme-Challenge", true), "example.com"),
+            "_Acme-Challenge.example.com"
+        );
+    }
+
+    #[test]
+    fn names_in_the_zone() {
+        let zones = ["example.com".to_string(), "example.org".to_string()];
+        for name in ["home", "a.b", "@", "", "home.example.com", "Example.COM."] {
+            assert!(check_zone(name, "example.com", &zones).is_ok(), "{name}");
+        }
+        // Not a known zone, so it is published under example.com
+        assert!(check_zone("home.example.net", "example.com", &zones).is_ok());
+    }
+
+    #[test]
+    fn names_in_another_zone() {
+        let zones = ["example.com".to_string(), "Example.ORG.".to_string()];
+        for name in ["home.example.org", "example.org", "Home.Example.Org."] {
+            let e = check_zone(name, "example.com", &zones).unwrap_err();
+            assert!(e.to_string().contains("belongs to zone example.org"), "{e}");
+        }
+    }
+
+    #[tokio::test]
+    async fn zones_are_known_up_front() {
+        let config = "[subdomain.vpn]\nzone_name = \"example.org\"\n\n\
+            [subdomain.\"home.example.org\"]\n";
+        let args = ["--zone-name", "example.com"];
+        let config = new_config("cf-ddns-zones.toml", config, &args).await;
+        // example.org is known before vpn's zone is fetched
+        let e = check_zone("home.example.org", "example.com", configured_zones(&config));
+        assert!(e.is_err());
+        assert!(check_zone("vpn", "example.org", configured_zones(&config)).is_ok());
+    }
+
+    #[test]
+    fn kept_ttl() {
+        // ttl = "keep" resolves to the default ttl, which isn't managed
+        let (ttl, manage_ttl) = Ttl::managed(Some(Ttl::Keep), true);
+        let wanted = WantedRecord {
+            ttl: ttl.unwrap_or(1),
+            manage_ttl,
+            ..cname("target.example.net", false)
+        };
+        assert!(!wanted.changes("target.example.net", 300, false, None).any());
+        assert_eq!(wanted.ttl_proxied(300, false), (300, Some(false)));
+    }
+
+    fn subdomain(config: &str) -> SubdomainsConfig {
+        toml::from_str(config).unwrap()
+    }
+
+    #[test]
+    fn cli_proxied_wins_over_globals() {
+        let global = subdomain("proxied = false\nproxied_a = false\nproxied_aaaa = false");
+        let config = SubdomainsConfig::default();
+        for ip_version in [None, Some(IP::V4), Some(IP::V6)] {
+            let proxied = |cli, global_schedule| {
+                resolve_proxied(
+                    "home",
+                    ip_version,
+                    &config,
+                    &global,
+                    cli,
+                    (None, global_schedule),
+                )
+            };
+            assert!(!proxied(None, None));
+            assert!(proxied(Some(true), None));
+            // The global schedule, which takes precedence over the global settings
+            assert!(proxied(None, Some(true)));
+            assert!(!proxied(Some(false), Some(true)));
+        }
+    }
+
+    #[test]
+    fn subdomain_proxied_wins_over_cli() {
+        let global = subdomain("proxied_a = true");
+        let config = subdomain("proxied = true\nproxied_aaaa = false");
+        let proxied = |ip_version, schedule| {
+            resolve_proxied(
+                "home",
+                ip_version,
+                &config,
+                &global,
+                Some(false),
+                (schedule, None),
+            )
+        };
+        assert!(proxied(Some(IP::V4), None));
+        assert!(!proxied(Some(IP::V6), None));
+        assert!(proxied(Some(IP::V6), Some(true)));
+        assert!(proxied(None, None));
+    }
+
+    #[test]
+    fn owner_tag() {
+        let tags = ["managed-by:cf-ddns".to_string(), "home".to_string()];
+        assert!(is_owned(None, &[]));
+        assert!(is_owned(Some("managed-by:cf-ddns"), &tags));
+        assert!(!is_owned(Some("managed-by:cf-ddns"), &tags[1..]));
+        assert!(!is_owned(Some("managed-by:cf-ddns"), &[]));
+    }
+
+    #[test]
+    fn priority_changes() {
+        let wanted = WantedRecord {
+            priority: Some(10),
+            ..cname("target.example.net", false)
+        };
+        assert!(!wanted
+            .changes("target.example.net", 1, false, Some(10))
+            .any());
+        for priority in [None, Some(20)] {
+            let changes = wanted.changes("target.example.net", 1, false, priority);
+            assert_eq!(changes.names(), ["priority"]);
+        }
+        // The record's priority is kept when none is configured
+        let wanted = cname("target.example.net", false);
+        assert!(!wanted
+            .changes("target.example.net", 1, false, Some(20))
+            .any());
+    }
+
+    #[test]
+    fn apex_aaaa() {
+        let ip: IpAddr = "2001:db8::1".parse().unwrap();
+        for name in ["@", "", "example.com", "example.com."] {
+            let fqdn = fqdn(name, "example.com");
+            let create = create_params(&fqdn, address_content(ip), 1, Some(true), None);
+            let update = update_params(&fqdn, address_content(ip), 1, Some(true));
+            // The bodies sent to Cloudflare
+            for body in [
+                serde_json::to_value(create).unwrap(),
+                serde_json::to_value(update).unwrap(),
+            ] {
+                assert_eq!(body["name"], "example.com", "{name:?}");
+                assert_eq!(body["type"], "AAAA");
+                assert_eq!(body["content"], "2001:db8::1");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn record_comments() {
+        let contents = r#"
+            [subdomains]
+            zone_id = "zone"
+            comment = "global"
+            comment_a = "global A"
+
+            [subdomain.alias]
+            cname = "target.example.net"
+            comment = "alias"
+
+            [subdomain.home]
+        "#;
+        let config = new_config("cf-ddns-comments.toml", contents, &[]).await;
+        let client = Client::new(config).unwrap();
+        let global = &client.config.subdomains_config;
+        let comment = |subdomain, type_| {
+            let config = &client.config.subdomains[subdomain];
+            client.record_comment(type_, config, global)
+        };
+        // The subdomain's comment is set on every type but the A records
+        assert_eq!(comment("alias", "CNAME").as_deref(), Some("alias"));
+        assert_eq!(comment("alias", "NS").as_deref(), Some("alias"));
+        assert_eq!(comment("alias", "AAAA").as_deref(), Some("alias"));
+        assert_eq!(comment("alias", "A").as_deref(), Some("alias"));
+        assert_eq!(comment("home", "A").as_deref(), Some("global A"));
+        assert_eq!(comment("home", "CNAME").as_deref(), Some("global"));
+    }
+
+    #[tokio::test]
+    async fn reset_ips_arent_checked() {
+        let ip_file = std::env::temp_dir().join("cf-ddns-reset-ips.txt");
+        std::fs::write(&ip_file, "198.51.100.1\n").unwrap();
+        let args = [
+            "--write-ip-file",
+            ip_file.to_str().unwrap(),
+            "--expect-current-ip",
+            "198.51.100.1",
+            "--max-change-abs",
+            "1",
+        ];
+        let config = new_config("cf-ddns-empty.toml", "", &args).await;
+        let mut client = Client::new(config).unwrap();
+        let reset: IpAddr = "203.0.113.1".parse().unwrap();
+        assert!(client.check_detected_ip(IP::V4, reset).is_err());
+
+        // The IP records are reset to isn't refused like a detected one
+        client.set_ip_provider(Some(Box::new(FixedIps(vec![reset]))));
+        let ip = client
+            .get_ip(IP::V4, IpSource::Cloudflare, None, None)
+            .await
+            .unwrap();
+        assert_eq!(ip, "203.0.113.1");
+    }
+
+    /// Counts its detections
+    struct CountingProvider(Rc<std::cell::Cell<usize>>);
+
+    impl IpProvider for CountingProvider {
+        fn detect(&self, _: IP) -> LocalBoxFuture<'_, Result<IpAddr>> {
+            self.0.set(self.0.get() + 1);
+            futures::future::ready(Ok("192.0.2.1".parse().unwrap())).boxed_local()
+        }
+    }
+
+    async fn detect(client: &mut Client) {
+        let ip = client.get_ip(IP::V4, IpSource::Cloudflare, None, None);
+        assert_eq!(ip.await.unwrap(), "192.0.2.1");
+    }
+
+    #[tokio::test]
+    async fn provider_ips_are_cached() {
+        let config = new_config("cf-ddns-empty.toml", "", &[]).await;
+        let mut client = Client::new(config).unwrap();
+        let detections = Rc::new(std::cell::Cell::new(0));
+
+        client.set_ip_provider(Some(Box::new(CountingProvider(detections.clone()))));
+        detect(&mut client).await;
+        detect(&mut client).await;
+        assert_eq!(detections.get(), 1);
+
+        // Until the next run or provider
+        client.clear_detected_ips();
+        detect(&mut client).await;
+        client.set_ip_provider(Some(Box::new(CountingProvider(detections.clone()))));
+        detect(&mut client).await;
+        assert_eq!(detections.get(), 3);
+    }
 }
@@ -1,28 +1,81 @@
 use cloudflare::framework::auth::Credentials;
-use color_eyre::eyre::bail;
-use std::{collections::HashMap, env, fs::File, io, path::PathBuf};
+use cloudflare::framework::HttpApiClientConfig;
+use color_eyre::eyre::{bail, ensure, eyre};
+use reqwest::header::{self, HeaderMap, HeaderValue};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    env,
+    ffi::OsStr,
+    fs::{self, File},
+    io, iter,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
-use clap::Parser;
-use color_eyre::{eyre::WrapErr, Result};
+use clap::{Parser, Subcommand, ValueEnum};
+use color_eyre::{
+    eyre::{ContextCompat, WrapErr},
+    Result,
+};
+use log::{debug, warn};
 use serde::Deserialize;
 
+use crate::trigger::TriggerMode;
+use crate::util::{
+    normalize_zone_name, write_atomic, EnsureSuccess, Expect, IpSource, MetadataProvider,
+};
+
 /// Cloudflare DDNS updater
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 pub struct Args {
-    /// Time To Live in seconds. Minimum 60, maximum 86400. 1 means auto
+    /// Time To Live in seconds. Minimum 60, maximum 86400. 1 means auto,
+    /// `keep` leaves the ttl of existing records as is
     #[arg(short, long)]
-    pub ttl: Option<u32>,
+    pub ttl: Option<Ttl>,
 
     /// Config file path. Default path is ~/.config/cf-ddns/config.toml
-    /// (XDG_CONFIG_HOME is used instead of ~/.config/ if set)
+    /// (XDG_CONFIG_HOME is used instead of ~/.config/ if set), falling back to
+    /// /etc/cf-ddns/config.toml.
+    /// TOML, YAML and JSON are supported, detected by the file extension.
+    /// Can be repeated or point to a directory (all config files in it are read
+    /// in alphabetical order). Later files override values from earlier ones
     #[arg(short, long = "config")]
-    pub config_path: Option<PathBuf>,
+    pub config_paths: Vec<PathBuf>,
+
+    /// Download the config from this URL. Config files given with -c are
+    /// applied on top of it
+    #[arg(long)]
+    pub config_from_url: Option<String>,
+
+    /// Save the config downloaded with --config-from-url to this file, and use
+    /// it when the download fails
+    #[arg(long, requires = "config_from_url")]
+    pub config_cache: Option<PathBuf>,
+
+    /// Fail if no config file is found or it has no subdomains, instead of
+    /// running with the defaults (e.g. when mounting the config failed). A
+    /// missing file is fine with --subdomain or --zone-id
+    #[arg(long)]
+    pub require_config: bool,
+
+    /// Add the subdomains listed in this file, one per line as `name` or
+    /// `name,type,proxied,ttl` (where type is A or AAAA and the other fields
+    /// can be empty). Subdomains in the config files are applied on top of them
+    #[arg(long, value_name = "PATH")]
+    pub subdomains_file: Option<PathBuf>,
 
     /// Cloudflare API Token
     #[arg(long, env = "CF_API_TOKEN")]
     pub api_token: Option<String>,
 
+    /// Read the API Token from the OS keyring entry `SERVICE/ACCOUNT` (the
+    /// service defaults to cf-ddns). Requires the keyring feature
+    #[arg(long, value_name = "[SERVICE/]ACCOUNT")]
+    pub api_token_keyring: Option<String>,
+
     /// Cloudflare API Key (must be used with Account Email)
     #[arg(long, env = "CF_API_KEY")]
     pub api_key: Option<String>,
@@ -32,10 +85,30 @@ pub struct Args {
     pub account_email: Option<String>,
 
     /// Zone Id
-    #[arg(long, env = "CF_ZONE_ID")]
+    #[arg(long, env = "CF_ZONE_ID", global = true)]
     pub zone_id: Option<String>,
 
-    /// Proxied
+    /// File containing the Zone Id
+    #[arg(long, env = "CF_ZONE_ID_FILE", global = true)]
+    pub zone_id_file: Option<PathBuf>,
+
+    /// Zone name (e.g. example.com). Used to look up the zone id if it isn't
+    /// specified, otherwise as the name of the zone, which then doesn't need to
+    /// be fetched
+    #[arg(long, env = "CF_ZONE_NAME", global = true)]
+    pub zone_name: Option<String>,
+
+    /// Name of the zone given by the zone id (e.g. example.com), which then
+    /// doesn't need to be fetched
+    #[arg(long, value_name = "DOMAIN")]
+    pub base_domain: Option<String>,
+
+    /// Cloudflare Account Id. Restricts zone name lookups to zones of this account
+    #[arg(long, env = "CF_ACCOUNT_ID", global = true)]
+    pub account_id: Option<String>,
+
+    /// Proxied. Takes precedence over the proxied settings and schedule of
+    /// [subdomains], but not over those of each subdomain
     #[arg(long)]
     pub proxied: Option<bool>,
 
@@ -51,71 +124,1065 @@ pub struct Args {
     /// Useful for debugging or running without a config file altogether
     #[arg(long)]
     pub subdomain: Option<String>,
+
+    /// Fail when a name has several A or AAAA records, instead of updating
+    /// the first one (same as multiple_records = "error"). Takes precedence
+    /// over multiple_records, even per subdomain
+    #[arg(long)]
+    pub strict_single: bool,
+
+    /// Fail instead of creating records that don't exist yet
+    #[arg(long)]
+    pub no_create: bool,
+
+    /// List the records again right before creating one, so records created
+    /// by another cf-ddns instance in the meantime aren't duplicated
+    #[arg(long)]
+    pub recheck_before_create: bool,
+
+    /// Only update A/AAAA records whose comment carries the cf-ddns marker,
+    /// failing on the others. Records created by cf-ddns get the marker
+    #[arg(long)]
+    pub require_marker: bool,
+
+    /// Warn and skip records without the marker instead of failing
+    #[arg(long, requires = "require_marker", conflicts_with = "adopt")]
+    pub skip_foreign: bool,
+
+    /// Stamp the marker on existing records that don't have it and update them
+    #[arg(long, requires = "require_marker")]
+    pub adopt: bool,
+
+    /// Tag the records cf-ddns creates with this tag (e.g.
+    /// managed-by:cf-ddns), and only delete stale records that carry it
+    #[arg(long, value_name = "TAG")]
+    pub owner_tag: Option<String>,
+
+    /// Push metrics to this statsd server (host:port) after each run
+    #[arg(long)]
+    pub statsd_addr: Option<String>,
+
+    /// Write the detected IPs (one per line) to this file after each run
+    #[arg(long)]
+    pub write_ip_file: Option<PathBuf>,
+
+    /// Write a `fqdn,type,id` line for each managed record to this file after
+    /// each run, or to stdout if it is `-`
+    #[arg(long, value_name = "PATH")]
+    pub write_record_id: Option<PathBuf>,
+
+    /// When IP detection fails, use the IP in --write-ip-file from a previous
+    /// run instead, if it isn't older than --last-known-max-age
+    #[arg(long, requires = "write_ip_file")]
+    pub use_last_known_on_failure: bool,
+
+    /// Maximum age in seconds of the IPs used by --use-last-known-on-failure
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value_t = 3600,
+        requires = "use_last_known_on_failure"
+    )]
+    pub last_known_max_age: u64,
+
+    /// Refuse to update the records with a detected IP that isn't one of
+    /// these. Only applies to the IP versions that have expected addresses
+    #[arg(long, value_name = "ADDR", value_delimiter = ',')]
+    pub expect_current_ip: Vec<IpAddr>,
+
+    /// Refuse to update the records with a detected IP further than this many
+    /// addresses from the one in --write-ip-file from a previous run. Remove
+    /// the file to accept a large change
+    #[arg(long, value_name = "ADDRESSES", requires = "write_ip_file")]
+    pub max_change_abs: Option<u128>,
+
+    /// Append a JSON line to this file for every record created or updated
+    #[arg(long)]
+    pub changelog: Option<PathBuf>,
+
+    /// Send a JSON report of each run to the process listening on this Unix socket
+    #[arg(long, value_name = "PATH")]
+    pub report_socket: Option<PathBuf>,
+
+    /// Run this shell command after updating, with the detected IPs in
+    /// CF_DDNS_IPV4/CF_DDNS_IPV6 and the JSON run report in CF_DDNS_REPORT.
+    /// cf-ddns exits with its exit code
+    #[arg(
+        long,
+        value_name = "COMMAND",
+        conflicts_with_all = ["interval", "diff_only"]
+    )]
+    pub then: Option<String>,
+
+    /// After updating, wait up to this many seconds for the new IPs to be
+    /// visible through the propagation resolvers
+    #[arg(long, value_name = "SECONDS")]
+    pub wait_propagation: Option<u64>,
+
+    /// Resolvers used to check propagation, queried concurrently
+    #[arg(
+        long,
+        alias = "propagation-resolver",
+        value_delimiter = ',',
+        default_value = "8.8.8.8"
+    )]
+    pub propagation_resolvers: Vec<IpAddr>,
+
+    /// Number of propagation resolvers that have to return the new IP before
+    /// a record counts as propagated [default: all of them]
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    pub propagation_quorum: Option<u64>,
+
+    /// Don't touch any record while this file exists: exit successfully right
+    /// away, or skip the updates with --interval
+    #[arg(long, value_name = "PATH")]
+    pub disable_file: Option<PathBuf>,
+
+    /// Only update the records when this file signals it (e.g. created by a
+    /// router's dyndns hook), otherwise exit successfully without doing anything
+    #[arg(long, value_name = "PATH")]
+    pub trigger_file: Option<PathBuf>,
+
+    /// How the trigger file signals an update
+    #[arg(long, value_enum, default_value_t, requires = "trigger_file")]
+    pub trigger_mode: TriggerMode,
+
+    /// Exit with code 0 on any error, including invalid configuration and
+    /// failed detections (errors are still logged). Invalid arguments and
+    /// --diff-only keep their exit codes
+    #[arg(long)]
+    pub soft_fail: bool,
+
+    /// Check the credentials, zones and IP detection, then exit without
+    /// changing any records
+    #[arg(long)]
+    pub preflight: bool,
+
+    /// Print the changes that would be made to the records without making them
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Only log unchanged records every this many runs with --interval, and
+    /// at debug level otherwise. Changes are always logged
+    #[arg(
+        long,
+        value_name = "N",
+        requires = "interval",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub log_unchanged_every: Option<u64>,
+
+    /// List the zones again on every run instead of once when running with --interval
+    #[arg(long, requires = "interval")]
+    pub refresh_zones: bool,
+
+    /// Like --dry-run, but exit with 2 if any record would change, 0 if all
+    /// of them are up to date and 1 on any error, including invalid arguments
+    /// and configuration
+    #[arg(long, conflicts_with = "interval")]
+    pub diff_only: bool,
+
+    /// Exit with this code (10 if not given) when the run succeeded and
+    /// created, updated or deleted any record. Errors keep their exit codes,
+    /// so 1 to 5 can't be used. Can't be combined with --then, whose command
+    /// decides the exit code
+    #[arg(
+        long,
+        value_name = "CODE",
+        num_args = 0..=1,
+        default_missing_value = "10",
+        value_parser = clap::value_parser!(u8).range(6..),
+        conflicts_with_all = ["interval", "diff_only", "then"]
+    )]
+    pub changed_exit_code: Option<u8>,
+
+    /// Log Cloudflare API requests and responses (target cf_ddns::api)
+    #[arg(long)]
+    pub trace_api: bool,
+
+    /// Where to get the IP address from. `local` uses the private address of
+    /// one of the machine's interfaces (for split-horizon setups), `metadata`
+    /// asks the instance metadata service of the cloud the machine runs on,
+    /// `upnp` the router of the local network (IPv4 only)
+    #[arg(long, value_enum)]
+    pub ip_source: Option<IpSource>,
+
+    /// Don't update A records when the detected IPv4 is behind carrier-grade
+    /// NAT (100.64.0.0/10), relying on the AAAA records instead
+    #[arg(long)]
+    pub prefer_ipv6: bool,
+
+    /// Timeout in seconds for each IP detection request
+    #[arg(long, value_name = "SECONDS", default_value_t = 10)]
+    pub timeout_detection: u64,
+
+    /// Maximum number of providers queried at the same time. Defaults to all of them
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    pub detect_concurrency: Option<usize>,
+
+    /// Accept invalid TLS certificates from the providers in [detection].
+    /// Never applies to Cloudflare
+    #[arg(long)]
+    pub insecure_ip_providers: bool,
+
+    /// User-Agent of the requests to Cloudflare and the IP providers
+    #[arg(long, default_value = concat!("cf-ddns/", env!("CARGO_PKG_VERSION")))]
+    pub user_agent: String,
+
+    /// Timeout in seconds for each Cloudflare API request
+    #[arg(long, value_name = "SECONDS", default_value_t = 30)]
+    pub timeout_api: u64,
+
+    /// Keep running, updating the records every this many seconds
+    #[arg(long, value_name = "SECONDS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub interval: Option<u64>,
+
+    /// With --interval, wait twice as long after each run where every
+    /// subdomain failed or the IP couldn't be detected, up to this many
+    /// seconds, until a run succeeds again. Not used with --watch-interface
+    #[arg(long, value_name = "SECONDS", default_value_t = 3600)]
+    pub max_backoff: u64,
+
+    /// Update the records once and exit. This is already the default without
+    /// --interval, but makes the intent explicit (e.g. in Kubernetes Jobs)
+    #[arg(long, conflicts_with = "interval")]
+    pub once: bool,
+
+    /// Give up on the run if it takes longer than this many seconds, failing
+    /// every subdomain
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        conflicts_with = "interval",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub max_runtime: Option<u64>,
+
+    /// What is printed to stdout after the run. Logs always go to stderr
+    #[arg(
+        long,
+        value_enum,
+        default_value_t,
+        conflicts_with_all = ["interval", "dry_run", "diff_only"]
+    )]
+    pub output: OutputFormat,
+
+    /// Format of the logs. With logfmt, each committed record is also logged
+    /// with its fqdn, type, action and old/new content as fields
+    #[arg(long, value_enum, default_value_t)]
+    pub log_format: LogFormat,
+
+    /// Update the records when an address of a network interface changes instead
+    /// of every --interval seconds. Falls back to the interval if unsupported
+    #[arg(long, requires = "interval")]
+    pub watch_interface: bool,
+
+    /// When the daemon is stopped, set the records to their `on_shutdown_ip`
+    #[arg(long, requires = "interval")]
+    pub reset_on_shutdown: bool,
+
+    /// Print the version, git commit, build date and cloudflare crate version as JSON
+    #[arg(long)]
+    pub print_version_info: bool,
+
+    /// Print a completion script for the given shell
+    #[arg(long, value_name = "SHELL")]
+    pub completion: Option<clap_complete::Shell>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(ValueEnum, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Nothing, only the logs
+    #[default]
+    Logs,
+    /// The JSON report of the run
+    Json,
+}
+
+#[derive(ValueEnum, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// env_logger's human readable format
+    #[default]
+    Text,
+    /// `key=value` pairs
+    Logfmt,
+}
+
+#[derive(Subcommand, Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// Print the zone's current A/AAAA records as cf-ddns config
+    Export,
+    /// Diagnose common misconfigurations without changing any records
+    Doctor,
+    /// Print the current records of every configured subdomain
+    Status {
+        /// Print the full records as JSON
+        #[arg(long)]
+        raw: bool,
+    },
+    /// Print the record types cf-ddns can manage and the config keys enabling them
+    SupportedTypes,
+    /// Report shadowed and contradictory settings of the config without
+    /// making any API request
+    Lint,
+    /// Print the record name and zone id of every configured subdomain as
+    /// JSON, without changing any records
+    Fqdns,
+    /// Print the current records of every configured subdomain as JSON, to
+    /// be compared later with `diff`
+    Snapshot,
+    /// Print the records added, removed or modified since a snapshot. Exits
+    /// with 1 if there are any
+    Diff {
+        /// Snapshot written by `cf-ddns snapshot`
+        #[arg(long, value_name = "PATH")]
+        against: PathBuf,
+    },
+}
+
+/// What to do when a name has several A or AAAA records
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum MultipleRecords {
+    /// Update the first one and warn
+    First,
+    /// Update all of them to the same content
+    All,
+    /// Fail
+    Error,
+}
+
+/// Transform of record content coming from templated sources
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Transform {
+    /// Remove leading and trailing whitespace
+    Trim,
+    Lowercase,
+    Uppercase,
+}
+
+impl Transform {
+    pub fn apply(self, content: &str) -> String {
+        match self {
+            Transform::Trim => content.trim().to_string(),
+            Transform::Lowercase => content.to_lowercase(),
+            Transform::Uppercase => content.to_uppercase(),
+        }
+    }
+}
+
+/// Ttl of the records
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(try_from = "FileTtl")]
+pub enum Ttl {
+    Seconds(u32),
+    /// Existing records keep their ttl, new ones get the default
+    Keep,
+}
+
+impl Ttl {
+    /// Seconds of the configured `ttl`, and whether the ttl of existing records
+    /// is updated per the `manage_ttl` setting, which `keep` overrides
+    pub fn managed(ttl: Option<Ttl>, manage_ttl: bool) -> (Option<u32>, bool) {
+        match ttl {
+            Some(Ttl::Seconds(seconds)) => (Some(seconds), manage_ttl),
+            Some(Ttl::Keep) => (None, false),
+            None => (None, manage_ttl),
+        }
+    }
+}
+
+impl std::str::FromStr for Ttl {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keep" => Ok(Ttl::Keep),
+            _ => s
+                .parse()
+                .map(Ttl::Seconds)
+                .map_err(|_| format!("invalid ttl {s:?}, expected seconds or \"keep\"")),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum FileTtl {
+    Seconds(u32),
+    Keyword(String),
+}
+
+impl TryFrom<FileTtl> for Ttl {
+    type Error = String;
+
+    fn try_from(ttl: FileTtl) -> Result<Self, Self::Error> {
+        match ttl {
+            FileTtl::Seconds(seconds) => Ok(Ttl::Seconds(seconds)),
+            FileTtl::Keyword(keyword) => keyword.parse(),
+        }
+    }
 }
 
 #[derive(Deserialize, Clone, Debug, Default)]
 pub struct SubdomainsConfig {
     pub zone_id: Option<String>,
-    pub ttl: Option<u32>,
+    /// File containing the zone id, used if zone_id isn't set
+    pub zone_id_file: Option<PathBuf>,
+    /// Name of the zone, looked up if zone_id isn't set. When it is, the zone
+    /// doesn't need to be read
+    pub zone_name: Option<String>,
+    /// Zones the record is committed to instead of a single zone_id/zone_name.
+    /// Only honored per subdomain
+    pub zone_ids: Option<Vec<String>>,
+    pub zone_names: Option<Vec<String>>,
+    pub ttl: Option<Ttl>,
+    /// Ttl of records that aren't proxied when `ttl` is unset, instead of auto
+    pub default_unproxied_ttl: Option<u32>,
     pub proxied: Option<bool>,
+    /// Override `proxied` for the A and AAAA records respectively
+    pub proxied_a: Option<bool>,
+    pub proxied_aaaa: Option<bool>,
+    /// Comment set on the A/AAAA records
+    pub comment: Option<String>,
+    /// Override `comment` for the A and AAAA records respectively
+    pub comment_a: Option<String>,
+    pub comment_aaaa: Option<String>,
+    /// Local time window (HH:MM-HH:MM) during which the record is proxied
+    pub proxied_schedule: Option<String>,
     pub a: Option<bool>,
     pub aaaa: Option<bool>,
+    pub create: Option<bool>,
+    /// Whether ttl/proxied of existing records are updated to the configured values
+    pub manage_ttl: Option<bool>,
+    pub manage_proxied: Option<bool>,
+    pub priority: Option<u16>,
+    /// Only honored per subdomain, overrides the global credentials
+    pub api_token: Option<String>,
+    /// Local address IP detection requests are sent from
+    pub source_address: Option<IpAddr>,
+    /// Override the detection source for the A and AAAA records respectively
+    pub ipv4_source: Option<IpSource>,
+    pub ipv6_source: Option<IpSource>,
+    /// Publish the network address of the detected IPv6, keeping this many bits
+    pub ipv6_network_bits: Option<u8>,
+    /// Only update the AAAA record when the first this many bits of the
+    /// detected IPv6 differ from it, ignoring the interface id
+    pub ipv6_compare_bits: Option<u8>,
+    /// Name servers to delegate the subdomain to, with NS records published
+    /// instead of A/AAAA records. Only honored per subdomain
+    pub ns: Option<Vec<String>>,
+    /// Rejected, see `Caa`
+    pub caa: Option<Caa>,
+    /// Target of a CNAME record published instead of A/AAAA records. Only
+    /// honored per subdomain
+    pub cname: Option<String>,
+    /// Transforms applied in order to the CNAME target before it is compared
+    /// with the record and committed
+    pub transform: Option<Vec<Transform>>,
+    /// Hostname the reverse names of the detected IPs point to, with PTR
+    /// records published instead of A/AAAA records. The zone must be the
+    /// reverse zone. Only honored per subdomain
+    pub ptr: Option<String>,
+    /// Origins published instead of the detected IPs, if they are healthy.
+    /// Only honored per subdomain
+    pub origins: Option<Vec<Origin>>,
+    /// Timeout in seconds of each origin health check
+    pub health_check_timeout: Option<u64>,
+    /// IPs the records are set to when the daemon shuts down with --reset-on-shutdown
+    pub on_shutdown_ip: Option<Vec<IpAddr>>,
+    /// Id of the A or AAAA record to update, instead of finding it by name.
+    /// Only honored per subdomain
+    pub record_id: Option<String>,
+    /// Don't lowercase the subdomain when building the record name
+    pub preserve_case: Option<bool>,
+    /// Revert the records updated in a run if another record of the subdomain
+    /// fails to be updated
+    pub atomic: Option<bool>,
+    /// What to do with names that have several records of the same type
+    pub multiple_records: Option<MultipleRecords>,
+    /// Times committing the subdomain is attempted again after failing
+    pub retries: Option<u32>,
+    /// Timeout in seconds of the API requests and IP detection of the
+    /// subdomain, instead of --timeout-api and --timeout-detection
+    pub timeout: Option<u64>,
+}
+
+/// CAA records can't be managed, as the cloudflare crate's `DnsContent` has no
+/// CAA variant. Configs setting them fail to parse instead of the key being
+/// ignored
+#[derive(Clone, Copy, Debug)]
+pub struct Caa;
+
+impl<'de> Deserialize<'de> for Caa {
+    fn deserialize<D: serde::Deserializer<'de>>(_: D) -> std::result::Result<Self, D::Error> {
+        Err(serde::de::Error::custom("CAA records aren't supported"))
+    }
+}
+
+impl SubdomainsConfig {
+    /// One config per zone in `zone_ids`/`zone_names`, along with the zone.
+    /// Just this config if neither is set
+    pub fn per_zone(&self) -> Vec<(Option<&str>, Cow<'_, SubdomainsConfig>)> {
+        let zone_ids = self.zone_ids.iter().flatten().map(|zone_id| {
+            let config = SubdomainsConfig {
+                zone_id: Some(zone_id.clone()),
+                zone_name: None,
+                ..self.clone()
+            };
+            (zone_id, config)
+        });
+        let zone_names = self.zone_names.iter().flatten().map(|zone_name| {
+            let config = SubdomainsConfig {
+                zone_id: None,
+                zone_name: Some(zone_name.clone()),
+                ..self.clone()
+            };
+            (zone_name, config)
+        });
+
+        let zones: Vec<_> = zone_ids
+            .chain(zone_names)
+            .map(|(zone, config)| (Some(zone.as_str()), Cow::Owned(config)))
+            .collect();
+        if zones.is_empty() {
+            vec![(None, Cow::Borrowed(self))]
+        } else {
+            zones
+        }
+    }
+
+    /// Values set in `other` take precedence
+    pub fn merge(self, other: SubdomainsConfig) -> SubdomainsConfig {
+        SubdomainsConfig {
+            zone_id: other.zone_id.or(self.zone_id),
+            zone_id_file: other.zone_id_file.or(self.zone_id_file),
+            zone_name: other.zone_name.or(self.zone_name),
+            zone_ids: other.zone_ids.or(self.zone_ids),
+            zone_names: other.zone_names.or(self.zone_names),
+            ttl: other.ttl.or(self.ttl),
+            default_unproxied_ttl: other.default_unproxied_ttl.or(self.default_unproxied_ttl),
+            proxied: other.proxied.or(self.proxied),
+            proxied_a: other.proxied_a.or(self.proxied_a),
+            proxied_aaaa: other.proxied_aaaa.or(self.proxied_aaaa),
+            comment: other.comment.or(self.comment),
+            comment_a: other.comment_a.or(self.comment_a),
+            comment_aaaa: other.comment_aaaa.or(self.comment_aaaa),
+            proxied_schedule: other.proxied_schedule.or(self.proxied_schedule),
+            a: other.a.or(self.a),
+            aaaa: other.aaaa.or(self.aaaa),
+            create: other.create.or(self.create),
+            manage_ttl: other.manage_ttl.or(self.manage_ttl),
+            manage_proxied: other.manage_proxied.or(self.manage_proxied),
+            priority: other.priority.or(self.priority),
+            api_token: other.api_token.or(self.api_token),
+            source_address: other.source_address.or(self.source_address),
+            ipv4_source: other.ipv4_source.or(self.ipv4_source),
+            ipv6_source: other.ipv6_source.or(self.ipv6_source),
+            ipv6_network_bits: other.ipv6_network_bits.or(self.ipv6_network_bits),
+            ipv6_compare_bits: other.ipv6_compare_bits.or(self.ipv6_compare_bits),
+            ns: other.ns.or(self.ns),
+            caa: other.caa.or(self.caa),
+            cname: other.cname.or(self.cname),
+            transform: other.transform.or(self.transform),
+            ptr: other.ptr.or(self.ptr),
+            origins: other.origins.or(self.origins),
+            health_check_timeout: other.health_check_timeout.or(self.health_check_timeout),
+            on_shutdown_ip: other.on_shutdown_ip.or(self.on_shutdown_ip),
+            record_id: other.record_id.or(self.record_id),
+            preserve_case: other.preserve_case.or(self.preserve_case),
+            atomic: other.atomic.or(self.atomic),
+            multiple_records: other.multiple_records.or(self.multiple_records),
+            retries: other.retries.or(self.retries),
+            timeout: other.timeout.or(self.timeout),
+        }
+    }
+}
+
+/// Health check of an origin, e.g. `{ tcp = 443 }` or `{ http = "https://origin/health" }`
+#[derive(Deserialize, Clone, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthCheck {
+    /// Connect to this port of the origin
+    Tcp(u16),
+    /// Request this URL from the origin, expecting a successful status code
+    Http(String),
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct Origin {
+    pub ip: IpAddr,
+    /// Origins without a health check are always published
+    pub health_check: Option<HealthCheck>,
 }
 
 #[derive(Deserialize, Debug, Default)]
-pub struct TomlConfig {
-    #[serde(rename = "subdomains")]
+pub struct FileConfig {
+    #[serde(rename = "subdomains", default)]
     pub subdomains_config: SubdomainsConfig,
-    #[serde(rename = "subdomain")]
+    #[serde(rename = "subdomain", default)]
     pub subdomains: HashMap<String, SubdomainsConfig>,
-    pub cloudflare: Option<TomlCloudflare>,
+    pub cloudflare: Option<FileCloudflare>,
+    pub detection: Option<FileDetection>,
+}
+
+impl FileConfig {
+    /// Values set in `other` take precedence. Subdomains defined in both are
+    /// replaced entirely by the ones in `other`
+    pub fn merge(mut self, other: FileConfig) -> FileConfig {
+        self.subdomains.extend(other.subdomains);
+
+        FileConfig {
+            subdomains_config: self.subdomains_config.merge(other.subdomains_config),
+            subdomains: self.subdomains,
+            cloudflare: match (self.cloudflare, other.cloudflare) {
+                (Some(cloudflare), Some(other)) => Some(cloudflare.merge(other)),
+                (cloudflare, other) => other.or(cloudflare),
+            },
+            detection: match (self.detection, other.detection) {
+                (Some(detection), Some(other)) => Some(detection.merge(other)),
+                (detection, other) => other.or(detection),
+            },
+        }
+    }
 }
 
 #[derive(Deserialize, Debug, Default)]
-pub struct TomlCloudflare {
+pub struct FileCloudflare {
     pub api_token: Option<String>,
     pub api_key: Option<String>,
     pub account_email: Option<String>,
+    pub account_id: Option<String>,
+}
+
+impl FileCloudflare {
+    pub fn merge(self, other: FileCloudflare) -> FileCloudflare {
+        FileCloudflare {
+            api_token: other.api_token.or(self.api_token),
+            api_key: other.api_key.or(self.api_key),
+            account_email: other.account_email.or(self.account_email),
+            account_id: other.account_id.or(self.account_id),
+        }
+    }
+}
+
+/// An IP provider, given either as a URL or as a table with extra request headers
+#[derive(Deserialize, Clone, Debug)]
+#[serde(from = "FileProvider")]
+pub struct Provider {
+    pub url: String,
+    pub headers: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum FileProvider {
+    Url(String),
+    Custom {
+        url: String,
+        #[serde(default)]
+        headers: HashMap<String, String>,
+    },
+}
+
+impl From<FileProvider> for Provider {
+    fn from(provider: FileProvider) -> Provider {
+        match provider {
+            FileProvider::Url(url) => Provider {
+                url,
+                headers: HashMap::new(),
+            },
+            FileProvider::Custom { url, headers } => Provider { url, headers },
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct FileDetection {
+    pub source: Option<IpSource>,
+    pub providers: Option<Vec<Provider>>,
+    pub min_agreement: Option<usize>,
+    pub metadata_provider: Option<MetadataProvider>,
+    pub expect: Option<Expect>,
+}
+
+impl FileDetection {
+    pub fn merge(self, other: FileDetection) -> FileDetection {
+        FileDetection {
+            source: other.source.or(self.source),
+            providers: other.providers.or(self.providers),
+            min_agreement: other.min_agreement.or(self.min_agreement),
+            metadata_provider: other.metadata_provider.or(self.metadata_provider),
+            expect: other.expect.or(self.expect),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Detection {
+    pub source: IpSource,
+    pub providers: Vec<Provider>,
+    pub min_agreement: usize,
+    /// Maximum number of providers queried at the same time
+    pub concurrency: usize,
+    /// Whether invalid TLS certificates of the providers are accepted
+    pub insecure: bool,
+    pub timeout: Duration,
+    pub user_agent: String,
+    /// Cloud queried by the metadata source. Detected if None
+    pub metadata_provider: Option<MetadataProvider>,
+    /// What detected IPs are expected to be
+    pub expect: Expect,
 }
 
 #[derive(Debug)]
 pub struct Cloudflare {
     pub auth: Credentials,
+    pub account_id: Option<String>,
+    pub timeout: Duration,
+    pub user_agent: HeaderValue,
 }
 
-pub fn get_toml_config_or_default(args: &Args) -> Result<TomlConfig> {
-    let config_file = match &args.config_path {
-        Some(config_path) => File::open(config_path),
-        None => {
-            let config_home = env::var("XDG_CONFIG_HOME").unwrap_or("~/.config/".to_string());
-            File::open(
-                PathBuf::from(config_home)
-                    .join("cf-ddns")
-                    .join("config.toml"),
+impl Cloudflare {
+    /// Settings of the HTTP client used for API requests
+    pub fn http_config(&self) -> HttpApiClientConfig {
+        HttpApiClientConfig {
+            http_timeout: self.timeout,
+            default_headers: HeaderMap::from_iter([(header::USER_AGENT, self.user_agent.clone())]),
+        }
+    }
+}
+
+/// Extensions of the supported config formats
+pub const CONFIG_EXTENSIONS: [&str; 4] = ["toml", "yaml", "yml", "json"];
+
+/// Renders line `line` of `config_data` with a caret under `column` (both
+/// 1-based), in the same format toml uses for its parse errors
+fn error_snippet(config_data: &str, line: usize, column: usize) -> String {
+    let text = config_data
+        .lines()
+        .nth(line.saturating_sub(1))
+        .unwrap_or_default();
+    let number = line.to_string();
+    let gutter = " ".repeat(number.len());
+    let caret = " ".repeat(column.saturating_sub(1));
+    format!("{gutter} |\n{number} | {text}\n{gutter} | {caret}^")
+}
+
+/// Parses the config according to the extension of `path`. Files without a
+/// known extension are parsed as TOML. toml errors already point at the
+/// offending line, so only YAML and JSON errors get a snippet added
+fn parse_config(path: &Path, config_data: &str) -> Result<FileConfig> {
+    Ok(match path.extension().and_then(OsStr::to_str) {
+        Some("yaml" | "yml") => {
+            serde_yaml::from_str(config_data).map_err(|e| match e.location() {
+                Some(location) => eyre!(
+                    "YAML parse error\n{}\n{e}",
+                    error_snippet(config_data, location.line(), location.column())
+                ),
+                None => e.into(),
+            })?
+        }
+        Some("json") => serde_json::from_str(config_data).map_err(|e| {
+            eyre!(
+                "JSON parse error\n{}\n{e}",
+                error_snippet(config_data, e.line(), e.column())
             )
+        })?,
+        _ => toml::from_str(config_data)?,
+    })
+}
+
+fn read_config_file(path: &Path, config_file: File) -> Result<FileConfig> {
+    let config_data = io::read_to_string(config_file)?;
+    parse_config(path, &config_data)
+}
+
+/// Downloads the config from `url`, whose format is detected by the extension
+/// of its path. If `cache` is set, successfully downloaded configs are saved
+/// there and used instead when the download fails
+async fn fetch_config(
+    url: &str,
+    cache: Option<&Path>,
+    timeout: Duration,
+    user_agent: &str,
+) -> Result<FileConfig> {
+    let parsed_url = reqwest::Url::parse(url).wrap_err_with(|| format!("Invalid URL {url}"))?;
+    let path = PathBuf::from(parsed_url.path());
+
+    let download = async {
+        reqwest::Client::builder()
+            .timeout(timeout)
+            .user_agent(user_agent)
+            .build()?
+            .get(parsed_url)
+            .send()
+            .await?
+            .ensure_success()?
+            .text()
+            .await
+            .map_err(Into::into)
+    };
+    let config_data: Result<String> = download.await;
+
+    let config_data = match (config_data, cache) {
+        (Ok(config_data), cache) => {
+            if let Some(cache) = cache {
+                if let Err(e) = write_atomic(cache, &config_data) {
+                    warn!("Failed to cache config: {e:?}");
+                }
+            }
+            config_data
         }
+        (Err(e), Some(cache)) if cache.exists() => {
+            warn!(
+                "Failed to download config from {url}, using the cached copy at {cache:?}: {e:?}"
+            );
+            fs::read_to_string(cache).wrap_err_with(|| format!("Failed to read {cache:?}"))?
+        }
+        (Err(e), _) => return Err(e.wrap_err(format!("Failed to download config from {url}"))),
     };
 
-    match config_file {
-        Ok(config_file) => {
-            let config_data = io::read_to_string(config_file)?;
-            Ok(toml::from_str(&config_data)?)
+    parse_config(&path, &config_data).wrap_err_with(|| format!("Failed to parse config from {url}"))
+}
+
+/// Files to read for a `-c` argument. Directories expand to the config files
+/// inside them, sorted by name
+fn config_files(config_path: &Path) -> Result<Vec<PathBuf>> {
+    if !config_path.is_dir() {
+        return Ok(vec![config_path.to_path_buf()]);
+    }
+
+    let mut files = fs::read_dir(config_path)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+    files.retain(|file| {
+        file.extension()
+            .and_then(OsStr::to_str)
+            .is_some_and(|ext| CONFIG_EXTENSIONS.contains(&ext))
+    });
+    files.sort();
+    Ok(files)
+}
+
+/// Config files looked for when none is given, in order of preference
+fn default_config_paths() -> Vec<PathBuf> {
+    let config_home = env::var("XDG_CONFIG_HOME").unwrap_or("~/.config/".to_string());
+    let default_dirs = [
+        PathBuf::from(config_home).join("cf-ddns"),
+        PathBuf::from("/etc/cf-ddns"),
+    ];
+
+    default_dirs
+        .into_iter()
+        .flat_map(|dir| {
+            CONFIG_EXTENSIONS
+                .into_iter()
+                .map(move |ext| dir.join("config").with_extension(ext))
+        })
+        .collect()
+}
+
+/// First config file that exists in the default locations
+pub fn default_config_path() -> Option<PathBuf> {
+    default_config_paths()
+        .into_iter()
+        .find(|path| path.is_file())
+}
+
+pub async fn get_file_config_or_default(args: &Args) -> Result<FileConfig> {
+    if args.config_paths.is_empty() && args.config_from_url.is_none() {
+        if let Some(path) = default_config_path() {
+            debug!("Loading config from {path:?}");
+            let config_file = File::open(&path)?;
+            return read_config_file(&path, config_file);
         }
-        Err(err) => {
-            if args.config_path.is_some() {
-                return Err(err).wrap_err("-c supplied but couldn't open file");
-            }
-            Ok(TomlConfig::default())
+
+        // Running without a config file is intended with these
+        let from_args = args.subdomain.is_some() || args.zone_id.is_some();
+        if args.require_config && !from_args {
+            bail!(
+                "No config file found (--require-config). Looked for {:?}",
+                default_config_paths()
+            );
+        }
+        debug!("No config file found, using defaults");
+        return Ok(FileConfig::default());
+    }
+
+    let mut config = match &args.config_from_url {
+        Some(url) => {
+            let timeout = Duration::from_secs(args.timeout_api);
+            fetch_config(url, args.config_cache.as_deref(), timeout, &args.user_agent).await?
+        }
+        None => FileConfig::default(),
+    };
+    for config_path in &args.config_paths {
+        let files = config_files(config_path)
+            .wrap_err_with(|| format!("-c supplied but couldn't read {config_path:?}"))?;
+
+        for file in files {
+            let config_file = File::open(&file).wrap_err("-c supplied but couldn't open file")?;
+            let file_config = read_config_file(&file, config_file)
+                .wrap_err_with(|| format!("Failed to parse config file {file:?}"))?;
+            config = config.merge(file_config);
+        }
+    }
+
+    Ok(config)
+}
+
+/// Substitutes `{hostname}` (the machine's hostname up to the first dot) and
+/// `{env:VAR}` in a subdomain name. Names without placeholders are returned
+/// as is, expanded ones must be valid DNS names
+fn expand_subdomain(name: &str) -> Result<String> {
+    if !name.contains('{') {
+        return Ok(name.to_string());
+    }
+
+    let mut expanded = String::new();
+    let mut rest = name;
+    while let Some(start) = rest.find('{') {
+        expanded += &rest[..start];
+        let Some(end) = rest[start..].find('}') else {
+            bail!("Unclosed placeholder in subdomain {name:?}");
+        };
+        let placeholder = &rest[start + 1..start + end];
+        if placeholder == "hostname" {
+            let hostname = gethostname::gethostname();
+            let hostname = hostname
+                .to_str()
+                .context("Hostname is not valid UTF-8")?
+                .split('.')
+                .next()
+                .unwrap_or_default();
+            expanded += &hostname.to_lowercase();
+        } else if let Some(var) = placeholder.strip_prefix("env:") {
+            expanded += &env::var(var).wrap_err_with(|| {
+                format!("Environment variable {var} used in subdomain {name:?} is not set")
+            })?;
+        } else {
+            bail!("Unknown placeholder {{{placeholder}}} in subdomain {name:?}");
+        }
+        rest = &rest[start + end + 1..];
+    }
+    expanded += rest;
+
+    for label in expanded.split('.') {
+        let valid = (1..=63).contains(&label.len())
+            && label
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            && !label.starts_with('-')
+            && !label.ends_with('-');
+        if !valid {
+            bail!("Subdomain {name:?} expands to {expanded:?}, which is not a valid DNS name");
+        }
+    }
+    Ok(expanded)
+}
+
+/// Reads a zone id from a file, e.g. a mounted secret
+fn read_zone_id_file(path: &Path) -> Result<String> {
+    let zone_id = fs::read_to_string(path)
+        .wrap_err_with(|| format!("Failed to read zone id file {path:?}"))?;
+    let zone_id = zone_id.trim();
+    ensure!(!zone_id.is_empty(), "Zone id file {path:?} is empty");
+    Ok(zone_id.to_string())
+}
+
+/// Subdomain of a `name,type,proxied,ttl` line of a --subdomains-file. `config`
+/// is the one of a previous line with the same name, whose types are kept
+fn parse_subdomain_line(
+    line: &str,
+    config: Option<SubdomainsConfig>,
+) -> Result<(String, SubdomainsConfig)> {
+    let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+    let (name, type_, proxied, ttl) = match fields[..] {
+        [name] => (name, "", "", ""),
+        [name, type_] => (name, type_, "", ""),
+        [name, type_, proxied] => (name, type_, proxied, ""),
+        [name, type_, proxied, ttl] => (name, type_, proxied, ttl),
+        _ => bail!(
+            "Expected at most 4 fields (name,type,proxied,ttl), got {}",
+            fields.len()
+        ),
+    };
+    ensure!(!name.is_empty(), "Missing subdomain name");
+
+    // Only the types listed for the name are enabled
+    let mut config = config.unwrap_or_default();
+    match type_.to_ascii_uppercase().as_str() {
+        "" => {}
+        "A" => {
+            config.a = Some(true);
+            config.aaaa.get_or_insert(false);
+        }
+        "AAAA" => {
+            config.aaaa = Some(true);
+            config.a.get_or_insert(false);
+        }
+        _ => bail!("Invalid record type {type_:?}, expected A or AAAA"),
+    }
+    if !proxied.is_empty() {
+        config.proxied = Some(
+            proxied
+                .parse()
+                .map_err(|_| eyre!("Invalid proxied {proxied:?}, expected true or false"))?,
+        );
+    }
+    if !ttl.is_empty() {
+        config.ttl = Some(ttl.parse().map_err(|e: String| eyre!(e))?);
+    }
+    Ok((name.to_string(), config))
+}
+
+/// Subdomains listed in a --subdomains-file. Empty lines and lines starting
+/// with `#` are skipped
+fn read_subdomains_file(path: &Path) -> Result<HashMap<String, SubdomainsConfig>> {
+    let contents = fs::read_to_string(path)
+        .wrap_err_with(|| format!("Failed to read subdomains file {path:?}"))?;
+    let mut subdomains = HashMap::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
         }
+        let name = line.split(',').next().unwrap_or_default().trim();
+        let (name, config) = parse_subdomain_line(line, subdomains.remove(name))
+            .wrap_err_with(|| format!("{path:?}, line {}: {line:?}", number + 1))?;
+        subdomains.insert(name, config);
     }
+    Ok(subdomains)
+}
+
+/// Api token stored in the OS keyring under `key`
+#[cfg(feature = "keyring")]
+fn keyring_token(key: &str) -> Result<String> {
+    let (service, account) = key.split_once('/').unwrap_or(("cf-ddns", key));
+    Ok(keyring::Entry::new(service, account)?.get_password()?)
+}
+
+#[cfg(not(feature = "keyring"))]
+fn keyring_token(_key: &str) -> Result<String> {
+    bail!("cf-ddns was built without the keyring feature")
 }
 
 pub trait NewCredentials {
     fn new(
         args_api_token: Option<String>,
+        args_api_token_keyring: Option<String>,
         args_api_key: Option<String>,
         args_account_email: Option<String>,
-        toml_cloudflare: Option<TomlCloudflare>,
+        file_cloudflare: Option<FileCloudflare>,
     ) -> Result<Self>
     where
         Self: Sized;
@@ -123,26 +1190,38 @@ pub trait NewCredentials {
 impl NewCredentials for Credentials {
     fn new(
         args_api_token: Option<String>,
+        args_api_token_keyring: Option<String>,
         args_api_key: Option<String>,
         args_account_email: Option<String>,
-        toml_cloudflare: Option<TomlCloudflare>,
+        file_cloudflare: Option<FileCloudflare>,
     ) -> Result<Credentials> {
-        let TomlCloudflare {
-            api_token: toml_api_token,
-            api_key: toml_api_key,
-            account_email: toml_account_email,
-        } = toml_cloudflare.unwrap_or_default();
+        let FileCloudflare {
+            api_token: file_api_token,
+            api_key: file_api_key,
+            account_email: file_account_email,
+            ..
+        } = file_cloudflare.unwrap_or_default();
 
-        if let Some(token) = args_api_token.or(toml_api_token) {
+        if let Some(key) = args_api_token_keyring {
+            match keyring_token(&key) {
+                Ok(token) => return Ok(Credentials::UserAuthToken { token }),
+                Err(e) => warn!(
+                    "Failed to read the api token from the keyring entry {key:?}, using the \
+                    other credentials: {e:#}"
+                ),
+            }
+        }
+
+        if let Some(token) = args_api_token.or(file_api_token) {
             return Ok(Credentials::UserAuthToken { token });
         }
 
-        let api_key = args_api_key.or(toml_api_key);
+        let api_key = args_api_key.or(file_api_key);
         let Some(api_key) = api_key else {
             bail!("Neither api token nor api key were specified");
         };
 
-        let account_email = args_account_email.or(toml_account_email);
+        let account_email = args_account_email.or(file_account_email);
         let Some(account_email) = account_email else {
             bail!("Account email not specified when api key was");
         };
@@ -159,55 +1238,427 @@ pub struct Config {
     pub cloudflare: Cloudflare,
     pub subdomains_config: SubdomainsConfig,
     pub subdomains: HashMap<String, SubdomainsConfig>,
+    /// Name of the global zone_id
+    pub base_domain: Option<String>,
+    /// --proxied, which takes precedence over the global proxied settings
+    /// and schedule
+    pub proxied: Option<bool>,
+    /// --strict-single, which takes precedence over every multiple_records
+    pub strict_single: bool,
+    pub detection: Detection,
+    pub recheck_before_create: bool,
+    pub require_marker: bool,
+    pub skip_foreign: bool,
+    pub adopt: bool,
+    pub owner_tag: Option<String>,
+    pub statsd_addr: Option<String>,
+    pub write_ip_file: Option<PathBuf>,
+    pub write_record_id: Option<PathBuf>,
+    /// Maximum age of the IPs in write_ip_file used when detection fails
+    pub last_known_max_age: Option<Duration>,
+    pub expect_current_ip: Vec<IpAddr>,
+    pub max_change_abs: Option<u128>,
+    pub report_socket: Option<PathBuf>,
+    pub then: Option<String>,
+    pub changelog: Option<PathBuf>,
+    pub wait_propagation: Option<Duration>,
+    pub propagation_resolvers: Vec<IpAddr>,
+    /// Number of resolvers a record has to be visible on
+    pub propagation_quorum: usize,
+    pub trace_api: bool,
+    pub dry_run: bool,
+    pub diff_only: bool,
+    pub changed_exit_code: Option<u8>,
+    pub refresh_zones: bool,
+    pub log_unchanged_every: Option<u64>,
+    pub prefer_ipv6: bool,
+    pub disable_file: Option<PathBuf>,
+    pub trigger_file: Option<PathBuf>,
+    pub trigger_mode: TriggerMode,
+    pub soft_fail: bool,
+    pub interval: Option<Duration>,
+    pub max_backoff: Duration,
+    pub max_runtime: Option<Duration>,
+    pub output: OutputFormat,
+    pub log_format: LogFormat,
+    pub watch_interface: bool,
+    pub reset_on_shutdown: bool,
 }
 
 impl Config {
-    pub fn new(args: Args) -> Result<Config> {
-        let toml = get_toml_config_or_default(&args)?;
+    pub async fn new(args: Args) -> Result<Config> {
+        let mut file_config = get_file_config_or_default(&args).await?;
+        if let Some(path) = &args.subdomains_file {
+            let mut subdomains = read_subdomains_file(path)?;
+            for (name, config) in file_config.subdomains.drain() {
+                let config = match subdomains.remove(&name) {
+                    Some(listed) => listed.merge(config),
+                    None => config,
+                };
+                subdomains.insert(name, config);
+            }
+            file_config.subdomains = subdomains;
+        }
+        for config in iter::once(&mut file_config.subdomains_config)
+            .chain(file_config.subdomains.values_mut())
+        {
+            if let (None, Some(path)) = (&config.zone_id, &config.zone_id_file) {
+                config.zone_id = Some(read_zone_id_file(path)?);
+            }
+            if let Some(bits) = config.ipv6_network_bits {
+                ensure!(
+                    bits <= 128,
+                    "ipv6_network_bits must be between 0 and 128, got {bits}"
+                );
+            }
+            if let Some(bits) = config.ipv6_compare_bits {
+                ensure!(
+                    bits <= 128,
+                    "ipv6_compare_bits must be between 0 and 128, got {bits}"
+                );
+            }
+            if let Some(ttl) = config.default_unproxied_ttl {
+                ensure!(
+                    (60..=86400).contains(&ttl),
+                    "default_unproxied_ttl must be between 60 and 86400, got {ttl}"
+                );
+            }
+        }
 
+        let account_id = args.account_id.or(file_config
+            .cloudflare
+            .as_ref()
+            .and_then(|cloudflare| cloudflare.account_id.clone()));
         let auth = Credentials::new(
             args.api_token,
+            args.api_token_keyring,
             args.api_key,
             args.account_email,
-            toml.cloudflare,
+            file_config.cloudflare,
         )?;
 
-        let subdomains_config = toml.subdomains_config;
-        let zone_id = args.zone_id.or(subdomains_config.zone_id);
+        let subdomains_config = file_config.subdomains_config;
+        let args_zone_id = match (args.zone_id, &args.zone_id_file) {
+            (None, Some(path)) => Some(read_zone_id_file(path)?),
+            (zone_id, _) => zone_id,
+        };
+        let zone_id = args_zone_id.or(subdomains_config.zone_id);
+        let zone_name = args.zone_name.or(subdomains_config.zone_name);
+        ensure!(
+            args.base_domain.is_none() || zone_id.is_some(),
+            "--base-domain requires a zone id"
+        );
 
-        if zone_id.is_none() {
-            // Check if all the subdomains have zone_id specified
-            let unspecified_zone_ids: Vec<&String> = toml
+        if zone_id.is_none() && zone_name.is_none() {
+            // Check if all the subdomains have zone_id or zone_name specified
+            let unspecified_zone_ids: Vec<&String> = file_config
                 .subdomains
                 .iter()
-                .filter(|(_, config)| config.zone_id.is_none())
+                .filter(|(_, config)| {
+                    config.zone_id.is_none()
+                        && config.zone_name.is_none()
+                        && config.zone_ids.is_none()
+                        && config.zone_names.is_none()
+                })
                 .map(|(name, _config)| name)
                 .collect();
 
             if !unspecified_zone_ids.is_empty() {
                 bail!(
-                    "zone_id not specified in toml or in arguments.
+                    "Neither zone_id nor zone_name specified in the config or in arguments.
                     Subdomains missing zone_ids: {unspecified_zone_ids:?}"
                 );
             }
         }
 
+        let detection = file_config.detection.unwrap_or_default();
+        let ip_source = args.ip_source.or(detection.source).unwrap_or_default();
+        let providers = detection.providers.unwrap_or_default();
+        // Simple majority unless specified
+        let min_agreement = detection.min_agreement.unwrap_or(providers.len() / 2 + 1);
+        let concurrency = args.detect_concurrency.unwrap_or(providers.len().max(1));
+
+        let source_overrides = file_config
+            .subdomains
+            .values()
+            .flat_map(|config| [config.ipv4_source, config.ipv6_source]);
+        let uses_providers = ip_source == IpSource::Providers
+            || [subdomains_config.ipv4_source, subdomains_config.ipv6_source]
+                .into_iter()
+                .chain(source_overrides)
+                .any(|source| source == Some(IpSource::Providers));
+        if uses_providers {
+            if providers.is_empty() {
+                bail!("IP source is providers but no providers were specified in [detection]");
+            }
+            if min_agreement == 0 || min_agreement > providers.len() {
+                bail!(
+                    "min_agreement must be between 1 and the number of providers ({}), \
+                    got {min_agreement}",
+                    providers.len()
+                );
+            }
+        }
+
+        if args.insecure_ip_providers {
+            warn!(
+                "TLS certificates of the IP providers are not verified \
+                (--insecure-ip-providers), their responses can be spoofed"
+            );
+        }
+
         let subdomains = if let Some(name) = args.subdomain {
             [(name, SubdomainsConfig::default())].into()
         } else {
-            toml.subdomains
+            file_config.subdomains
+        };
+        let subdomains = subdomains
+            .into_iter()
+            .map(|(name, config)| Ok((expand_subdomain(&name)?, config)))
+            .collect::<Result<HashMap<_, _>>>()?;
+        ensure!(
+            !args.require_config || !subdomains.is_empty(),
+            "No subdomains are configured (--require-config)"
+        );
+        // A resolver listed twice would count twice towards the quorum
+        let mut propagation_resolvers = Vec::new();
+        for resolver in args.propagation_resolvers {
+            if !propagation_resolvers.contains(&resolver) {
+                propagation_resolvers.push(resolver);
+            }
+        }
+        let propagation_quorum = match args.propagation_quorum {
+            Some(quorum) => {
+                ensure!(
+                    quorum as usize <= propagation_resolvers.len(),
+                    "Propagation quorum {quorum} is larger than the number of resolvers ({})",
+                    propagation_resolvers.len()
+                );
+                quorum as usize
+            }
+            None => propagation_resolvers.len(),
         };
 
         Ok(Self {
-            cloudflare: Cloudflare { auth },
+            cloudflare: Cloudflare {
+                auth,
+                account_id,
+                timeout: Duration::from_secs(args.timeout_api),
+                user_agent: HeaderValue::from_str(&args.user_agent)
+                    .wrap_err_with(|| format!("Invalid user agent {:?}", args.user_agent))?,
+            },
             subdomains_config: SubdomainsConfig {
                 zone_id,
+                zone_id_file: None,
+                zone_name,
+                zone_ids: None,
+                zone_names: None,
                 ttl: args.ttl.or(subdomains_config.ttl),
-                proxied: args.proxied.or(subdomains_config.proxied),
+                default_unproxied_ttl: subdomains_config.default_unproxied_ttl,
+                proxied: subdomains_config.proxied,
+                proxied_a: subdomains_config.proxied_a,
+                proxied_aaaa: subdomains_config.proxied_aaaa,
+                comment: subdomains_config.comment,
+                comment_a: subdomains_config.comment_a,
+                comment_aaaa: subdomains_config.comment_aaaa,
+                proxied_schedule: subdomains_config.proxied_schedule,
                 a: args.a.or(subdomains_config.a),
                 aaaa: args.aaaa.or(subdomains_config.aaaa),
+                create: args.no_create.then_some(false).or(subdomains_config.create),
+                manage_ttl: subdomains_config.manage_ttl,
+                manage_proxied: subdomains_config.manage_proxied,
+                priority: subdomains_config.priority,
+                api_token: None,
+                source_address: subdomains_config.source_address,
+                ipv4_source: subdomains_config.ipv4_source,
+                ipv6_source: subdomains_config.ipv6_source,
+                ipv6_network_bits: subdomains_config.ipv6_network_bits,
+                ipv6_compare_bits: subdomains_config.ipv6_compare_bits,
+                ns: None,
+                caa: None,
+                cname: None,
+                transform: subdomains_config.transform,
+                ptr: None,
+                origins: None,
+                health_check_timeout: subdomains_config.health_check_timeout,
+                on_shutdown_ip: subdomains_config.on_shutdown_ip,
+                record_id: None,
+                preserve_case: subdomains_config.preserve_case,
+                atomic: subdomains_config.atomic,
+                multiple_records: subdomains_config.multiple_records,
+                retries: subdomains_config.retries,
+                timeout: subdomains_config.timeout,
             },
             subdomains,
+            base_domain: args.base_domain.map(|domain| normalize_zone_name(&domain)),
+            proxied: args.proxied,
+            strict_single: args.strict_single,
+            detection: Detection {
+                source: ip_source,
+                providers,
+                min_agreement,
+                concurrency,
+                insecure: args.insecure_ip_providers,
+                timeout: Duration::from_secs(args.timeout_detection),
+                user_agent: args.user_agent.clone(),
+                metadata_provider: detection.metadata_provider,
+                expect: detection.expect.unwrap_or_default(),
+            },
+            recheck_before_create: args.recheck_before_create,
+            require_marker: args.require_marker,
+            skip_foreign: args.skip_foreign,
+            adopt: args.adopt,
+            owner_tag: args.owner_tag,
+            statsd_addr: args.statsd_addr,
+            write_ip_file: args.write_ip_file,
+            write_record_id: args.write_record_id,
+            last_known_max_age: args
+                .use_last_known_on_failure
+                .then(|| Duration::from_secs(args.last_known_max_age)),
+            expect_current_ip: args.expect_current_ip,
+            max_change_abs: args.max_change_abs,
+            report_socket: args.report_socket,
+            then: args.then,
+            changelog: args.changelog,
+            wait_propagation: args.wait_propagation.map(Duration::from_secs),
+            propagation_resolvers,
+            propagation_quorum,
+            trace_api: args.trace_api,
+            dry_run: args.dry_run || args.diff_only,
+            diff_only: args.diff_only,
+            changed_exit_code: args.changed_exit_code,
+            refresh_zones: args.refresh_zones,
+            log_unchanged_every: args.log_unchanged_every,
+            prefer_ipv6: args.prefer_ipv6,
+            disable_file: args.disable_file,
+            trigger_file: args.trigger_file,
+            trigger_mode: args.trigger_mode,
+            soft_fail: args.soft_fail,
+            interval: args.interval.map(Duration::from_secs),
+            max_backoff: Duration::from_secs(args.max_backoff),
+            max_runtime: args.max_runtime.map(Duration::from_secs),
+            output: args.output,
+            log_format: args.log_format,
+            watch_interface: args.watch_interface,
+            reset_on_shutdown: args.reset_on_shutdown,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ttls() {
+        assert_eq!("keep".parse(), Ok(Ttl::Keep));
+        assert_eq!("300".parse(), Ok(Ttl::Seconds(300)));
+        for ttl in ["Keep", "", "-1", "5m"] {
+            assert!(ttl.parse::<Ttl>().is_err(), "{ttl:?}");
+        }
+        assert_eq!(Ttl::try_from(FileTtl::Seconds(60)), Ok(Ttl::Seconds(60)));
+        let keyword = |keyword: &str| Ttl::try_from(FileTtl::Keyword(keyword.to_string()));
+        assert_eq!(keyword("keep"), Ok(Ttl::Keep));
+        assert!(keyword("auto").is_err());
+    }
+
+    #[test]
+    fn ttls_in_config() {
+        let config: FileConfig = toml::from_str(
+            "[subdomains]\nttl = \"keep\"\n\
+            [subdomain.home]\nttl = 300\n\
+            [subdomain.vpn]\nttl = \"300\"\n",
+        )
+        .unwrap();
+        assert_eq!(config.subdomains_config.ttl, Some(Ttl::Keep));
+        assert_eq!(config.subdomains["home"].ttl, Some(Ttl::Seconds(300)));
+        assert_eq!(config.subdomains["vpn"].ttl, Some(Ttl::Seconds(300)));
+        assert!(toml::from_str::<FileConfig>("[subdomains]\nttl = \"auto\"\n").is_err());
+    }
+
+    #[test]
+    fn keep_ttl_isnt_managed() {
+        assert_eq!(Ttl::managed(Some(Ttl::Keep), true), (None, false));
+        assert_eq!(
+            Ttl::managed(Some(Ttl::Seconds(300)), true),
+            (Some(300), true)
+        );
+        assert_eq!(
+            Ttl::managed(Some(Ttl::Seconds(300)), false),
+            (Some(300), false)
+        );
+        assert_eq!(Ttl::managed(None, true), (None, true));
+        assert_eq!(Ttl::managed(None, false), (None, false));
+    }
+
+    async fn new_config(args: &[&str]) -> Result<Config> {
+        let empty = std::env::temp_dir().join("cf-ddns-empty.toml");
+        fs::write(&empty, "").unwrap();
+        let empty = empty.to_str().unwrap();
+        let base = ["cf-ddns", "--config", empty, "--api-token", "token"];
+        Config::new(Args::parse_from(base.iter().chain(args))).await
+    }
+
+    #[tokio::test]
+    async fn duplicate_resolvers() {
+        let resolvers = ["--propagation-resolvers", "1.1.1.1,8.8.8.8,1.1.1.1"];
+        let config = new_config(&resolvers).await.unwrap();
+        let resolvers: Vec<IpAddr> = vec!["1.1.1.1".parse().unwrap(), "8.8.8.8".parse().unwrap()];
+        assert_eq!(config.propagation_resolvers, resolvers);
+        assert_eq!(config.propagation_quorum, 2);
+
+        let quorum = [
+            "--propagation-resolvers",
+            "1.1.1.1,1.1.1.1",
+            "--propagation-quorum",
+            "2",
+        ];
+        assert!(new_config(&quorum).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn require_config_with_args() {
+        let args = [
+            "cf-ddns",
+            "--api-token",
+            "token",
+            "--require-config",
+            "--subdomain",
+            "home",
+        ];
+        let config = Config::new(Args::parse_from(args)).await.unwrap();
+        assert_eq!(config.subdomains.len(), 1);
+
+        // Without a subdomain there's nothing to update
+        let args = ["cf-ddns", "--api-token", "token", "--require-config"];
+        let zone_id = [&args[..], &["--zone-id", "zone"]].concat();
+        let e = Config::new(Args::parse_from(zone_id)).await.unwrap_err();
+        assert!(e.to_string().contains("No subdomains"), "{e}");
+    }
+
+    #[test]
+    fn changed_exit_codes() {
+        let parse = |code| Args::try_parse_from(["cf-ddns", "--changed-exit-code", code]);
+        // 1 to 5 are the error exit codes
+        for code in ["0", "1", "5"] {
+            assert!(parse(code).is_err(), "{code}");
+        }
+        assert_eq!(parse("6").unwrap().changed_exit_code, Some(6));
+        let args = Args::try_parse_from(["cf-ddns", "--changed-exit-code"]).unwrap();
+        assert_eq!(args.changed_exit_code, Some(10));
+        // --then's command decides the exit code
+        let then = ["cf-ddns", "--changed-exit-code", "--then", "true"];
+        let e = Args::try_parse_from(then).unwrap_err();
+        assert_eq!(e.kind(), clap::error::ErrorKind::ArgumentConflict);
+    }
+
+    #[test]
+    fn caa_is_rejected() {
+        let config = "[subdomain.home]\ncaa = [\"0 issue letsencrypt.org\"]\n";
+        let e = toml::from_str::<FileConfig>(config).unwrap_err();
+        assert!(
+            e.to_string().contains("CAA records aren't supported"),
+            "{e}"
+        );
+    }
+}
@@ -0,0 +1,141 @@
+use std::env;
+use std::path::PathBuf;
+
+use color_eyre::Result;
+
+use crate::client::Client;
+use crate::config::*;
+use crate::lint;
+
+/// Prints check results as a checklist, remembering whether any failed
+#[derive(Default)]
+pub struct Checklist {
+    failed: bool,
+}
+
+impl Checklist {
+    pub fn pass(&mut self, check: &str, detail: &str) {
+        println!("[PASS] {check}: {detail}");
+    }
+
+    pub fn warn(&mut self, check: &str, detail: &str, hint: &str) {
+        println!("[WARN] {check}: {detail}\n       hint: {hint}");
+    }
+
+    pub fn fail(&mut self, check: &str, detail: &str, hint: &str) {
+        println!("[FAIL] {check}: {detail}\n       hint: {hint}");
+        self.failed = true;
+    }
+
+    /// Passes with the value of `result` or fails with its error
+    pub fn check(&mut self, check: &str, result: Result<String>, hint: &str) {
+        match result {
+            Ok(detail) => self.pass(check, &detail),
+            Err(e) => self.fail(check, &format!("{e:#}"), hint),
+        }
+    }
+
+    pub fn passed(&self) -> bool {
+        !self.failed
+    }
+}
+
+fn check_config_path(args: &Args, checklist: &mut Checklist) {
+    if let Some(url) = &args.config_from_url {
+        checklist.pass("config", &format!("downloaded from {url}"));
+    }
+    for path in &args.config_paths {
+        if path.exists() {
+            checklist.pass("config", &format!("read from {path:?}"));
+        } else {
+            checklist.fail(
+                "config",
+                &format!("{path:?} doesn't exist"),
+                "check the path passed to -c",
+            );
+        }
+    }
+    if args.config_from_url.is_some() || !args.config_paths.is_empty() {
+        return;
+    }
+
+    match default_config_path() {
+        Some(path) => checklist.pass("config", &format!("read from {path:?}")),
+        None => checklist.warn(
+            "config",
+            "no config file found, running with command line arguments only",
+            "create ~/.config/cf-ddns/config.toml or /etc/cf-ddns/config.toml, or pass -c",
+        ),
+    }
+
+    // The default path falls back to a literal ~/.config/ which the shell
+    // never gets to expand
+    if env::var_os("XDG_CONFIG_HOME").is_none() {
+        let Some(home) = env::var_os("HOME") else {
+            return;
+        };
+        let config_dir = PathBuf::from(home).join(".config").join("cf-ddns");
+        for ext in CONFIG_EXTENSIONS {
+            let path = config_dir.join("config").with_extension(ext);
+            if path.exists() {
+                checklist.warn(
+                    "config",
+                    &format!("{path:?} exists but isn't read because XDG_CONFIG_HOME is not set"),
+                    &format!("set XDG_CONFIG_HOME=$HOME/.config or pass -c {path:?}"),
+                );
+            }
+        }
+    }
+}
+
+/// Proxied records always use automatic ttl, so a configured one is ignored
+fn check_proxied_ttl(config: &Config, checklist: &mut Checklist) {
+    // --proxied takes precedence over the global setting
+    let global = &SubdomainsConfig {
+        proxied: config.proxied.or(config.subdomains_config.proxied),
+        ..config.subdomains_config.clone()
+    };
+    let mut subdomains: Vec<_> = config.subdomains.iter().collect();
+    subdomains.sort_by_key(|(subdomain, _)| *subdomain);
+
+    for (subdomain, config) in subdomains {
+        if let Some(warning) = lint::proxied_ttl(config, global) {
+            checklist.warn(
+                &format!("ttl of subdomain {subdomain:?}"),
+                &warning,
+                "set ttl = 1 (auto) or proxied = false",
+            );
+        }
+    }
+}
+
+/// Diagnoses common misconfigurations without changing any records. Returns
+/// whether there were no hard failures
+pub async fn doctor(args: Args) -> bool {
+    let mut checklist = Checklist::default();
+    check_config_path(&args, &mut checklist);
+
+    let config = match Config::new(args).await {
+        Ok(config) => {
+            checklist.pass("config", "valid");
+            config
+        }
+        Err(e) => {
+            checklist.fail(
+                "config",
+                &format!("{e:#}"),
+                "fix the config or the arguments, credentials can also be set with \
+                CF_API_TOKEN or CF_API_KEY and CF_ACCOUNT_EMAIL",
+            );
+            return false;
+        }
+    };
+    check_proxied_ttl(&config, &mut checklist);
+
+    match Client::new(config) {
+        Ok(mut client) => client.preflight(&mut checklist).await,
+        Err(e) => checklist.fail("client", &format!("{e:#}"), "check the TLS setup"),
+    }
+
+    checklist.passed()
+}
@@ -0,0 +1,187 @@
+use std::fmt::{self, Display};
+use std::process::ExitCode;
+
+use cloudflare::framework::response::ApiFailure;
+use color_eyre::Report;
+use reqwest::StatusCode;
+
+/// Errors with a dedicated exit code that can't be told apart by their
+/// source. Attached to errors with `wrap_err`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    Config,
+    Detection,
+}
+
+impl Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorKind::Config => write!(f, "Invalid configuration"),
+            ErrorKind::Detection => write!(f, "IP detection failed"),
+        }
+    }
+}
+
+/// Exit codes, documented in the README
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ExitStatus {
+    Success = 0,
+    /// Some subdomains failed
+    PartialFailure = 1,
+    /// All subdomains failed, for different reasons
+    TotalFailure = 2,
+    Config = 3,
+    /// Cloudflare rejected the credentials
+    Auth = 4,
+    Detection = 5,
+}
+
+impl ExitStatus {
+    pub fn from_error(error: &Report) -> ExitStatus {
+        match error.downcast_ref::<ErrorKind>() {
+            Some(ErrorKind::Config) => return ExitStatus::Config,
+            Some(ErrorKind::Detection) => return ExitStatus::Detection,
+            None => {}
+        }
+
+        let is_auth = error.chain().any(|e| {
+            matches!(
+                e.downcast_ref::<ApiFailure>(),
+                Some(ApiFailure::Error(
+                    StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN,
+                    _
+                ))
+            )
+        });
+        if is_auth {
+            ExitStatus::Auth
+        } else {
+            ExitStatus::TotalFailure
+        }
+    }
+
+    /// Status of a run over `total` subdomains, where the ones in `failures`
+    /// failed. When all of them failed for the same reason, that reason is used
+    pub fn from_run(total: usize, failures: &[ExitStatus]) -> ExitStatus {
+        match failures {
+            [] => ExitStatus::Success,
+            _ if failures.len() < total => ExitStatus::PartialFailure,
+            [first, rest @ ..] if rest.iter().all(|status| status == first) => *first,
+            _ => ExitStatus::TotalFailure,
+        }
+    }
+}
+
+/// Exit code of --diff-only when records would change
+pub const DRIFT: u8 = 2;
+
+/// Flags that change how statuses map to exit codes. Read from the arguments,
+/// so they also apply to errors before the configuration is loaded
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ExitMode {
+    /// Every error exits with 1, as 2 is taken by drift
+    pub diff_only: bool,
+    /// Every error exits with 0, unless --diff-only is set
+    pub soft_fail: bool,
+}
+
+impl ExitMode {
+    /// Exit code of a run, or of an error that stopped it, with `status`
+    pub fn code(self, status: ExitStatus) -> u8 {
+        match status {
+            ExitStatus::Success => 0,
+            _ if self.diff_only => 1,
+            _ if self.soft_fail => 0,
+            status => status as u8,
+        }
+    }
+}
+
+impl From<ExitStatus> for ExitCode {
+    fn from(status: ExitStatus) -> ExitCode {
+        (status as u8).into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cloudflare::framework::response::ApiErrors;
+    use color_eyre::eyre::eyre;
+
+    use super::*;
+
+    fn api_error(status: StatusCode) -> Report {
+        let errors = ApiErrors {
+            errors: Vec::new(),
+            other: Default::default(),
+        };
+        Report::new(ApiFailure::Error(status, errors)).wrap_err("Failed to update record")
+    }
+
+    #[test]
+    fn error_statuses() {
+        let config = eyre!("zone_id is not set").wrap_err(ErrorKind::Config);
+        assert_eq!(ExitStatus::from_error(&config), ExitStatus::Config);
+        let detection = eyre!("No provider answered").wrap_err(ErrorKind::Detection);
+        assert_eq!(ExitStatus::from_error(&detection), ExitStatus::Detection);
+        for status in [StatusCode::UNAUTHORIZED, StatusCode::FORBIDDEN] {
+            assert_eq!(ExitStatus::from_error(&api_error(status)), ExitStatus::Auth);
+        }
+        let other = api_error(StatusCode::BAD_REQUEST);
+        assert_eq!(ExitStatus::from_error(&other), ExitStatus::TotalFailure);
+        let other = eyre!("Failed to connect");
+        assert_eq!(ExitStatus::from_error(&other), ExitStatus::TotalFailure);
+    }
+
+    #[test]
+    fn run_statuses() {
+        use ExitStatus::*;
+
+        assert_eq!(ExitStatus::from_run(0, &[]), Success);
+        assert_eq!(ExitStatus::from_run(3, &[]), Success);
+        assert_eq!(ExitStatus::from_run(3, &[Auth]), PartialFailure);
+        assert_eq!(ExitStatus::from_run(3, &[Auth, Auth]), PartialFailure);
+        // Every subdomain failed, for the same reason
+        assert_eq!(ExitStatus::from_run(2, &[Auth, Auth]), Auth);
+        assert_eq!(ExitStatus::from_run(1, &[Config]), Config);
+        assert_eq!(ExitStatus::from_run(2, &[Detection, Detection]), Detection);
+        // or for different ones
+        assert_eq!(ExitStatus::from_run(2, &[Auth, Detection]), TotalFailure);
+        assert_eq!(ExitStatus::from_run(1, &[TotalFailure]), TotalFailure);
+    }
+
+    #[test]
+    fn diff_only_codes() {
+        use ExitStatus::*;
+
+        let diff_only = ExitMode {
+            diff_only: true,
+            ..Default::default()
+        };
+        assert_eq!(diff_only.code(Success), 0);
+        for status in [PartialFailure, TotalFailure, Config, Auth, Detection] {
+            assert_eq!(diff_only.code(status), 1, "{status:?}");
+            assert_eq!(ExitMode::default().code(status), status as u8);
+        }
+    }
+
+    #[test]
+    fn soft_fail_codes() {
+        use ExitStatus::*;
+
+        let soft_fail = ExitMode {
+            soft_fail: true,
+            ..Default::default()
+        };
+        let both = ExitMode {
+            diff_only: true,
+            soft_fail: true,
+        };
+        for status in [PartialFailure, TotalFailure, Config, Auth, Detection] {
+            assert_eq!(soft_fail.code(status), 0, "{status:?}");
+            // A failed check can't pass for "no drift"
+            assert_eq!(both.code(status), 1, "{status:?}");
+        }
+    }
+}
@@ -0,0 +1,262 @@
+use color_eyre::Result;
+
+use crate::config::*;
+
+/// Settings only honored per subdomain, as they appear in the config
+const PER_SUBDOMAIN_ONLY: [&str; 6] = ["api_token", "ns", "cname", "ptr", "origins", "record_id"];
+
+/// Settings of the global `[subdomains]` table that are ignored
+fn ignored_global(global: &SubdomainsConfig) -> Vec<String> {
+    let set = [
+        global.api_token.is_some(),
+        global.ns.is_some(),
+        global.cname.is_some(),
+        global.ptr.is_some(),
+        global.origins.is_some(),
+        global.record_id.is_some(),
+    ];
+    PER_SUBDOMAIN_ONLY
+        .iter()
+        .zip(set)
+        .filter(|(_, set)| *set)
+        .map(|(key, _)| {
+            format!("{key} is only honored per subdomain and is ignored in [subdomains]")
+        })
+        .collect()
+}
+
+/// Record types enabled in the subdomain although they are disabled globally
+fn overridden_disable(config: &SubdomainsConfig, global: &SubdomainsConfig) -> Vec<String> {
+    [
+        ("a", config.a, global.a),
+        ("aaaa", config.aaaa, global.aaaa),
+    ]
+    .into_iter()
+    .filter(|(_, per_subdomain, global)| *per_subdomain == Some(true) && *global == Some(false))
+    .map(|(key, ..)| format!("{key} = true overrides {key} = false in [subdomains]"))
+    .collect()
+}
+
+/// Proxied records always use automatic ttl, so a configured one is ignored
+pub fn proxied_ttl(config: &SubdomainsConfig, global: &SubdomainsConfig) -> Option<String> {
+    let proxied = config.proxied.or(global.proxied).unwrap_or(true)
+        || config.proxied_schedule.is_some()
+        || global.proxied_schedule.is_some();
+    match config.ttl.or(global.ttl) {
+        Some(Ttl::Seconds(ttl)) if proxied && ttl != 1 => Some(format!(
+            "ttl = {ttl} is ignored while the record is proxied"
+        )),
+        _ => None,
+    }
+}
+
+/// A and AAAA explicitly enabled on a subdomain publishing a CNAME, origins or
+/// NS records instead
+fn ignored_address_types(config: &SubdomainsConfig) -> Vec<String> {
+    let replacement = match (&config.cname, &config.origins, &config.ns) {
+        (Some(_), ..) => "cname",
+        (None, Some(_), _) => "origins",
+        (None, None, Some(_)) => "ns",
+        (None, None, None) => return Vec::new(),
+    };
+    [("a", config.a), ("aaaa", config.aaaa)]
+        .into_iter()
+        .filter(|(_, enabled)| *enabled == Some(true))
+        .map(|(key, _)| format!("{key} = true is ignored because {replacement} is set"))
+        .collect()
+}
+
+/// Subdomains that don't publish any record
+fn no_records(config: &SubdomainsConfig, global: &SubdomainsConfig) -> Option<String> {
+    let a = config.a.or(global.a).unwrap_or(true);
+    let aaaa = config.aaaa.or(global.aaaa).unwrap_or(false);
+    let other = config.ns.is_some()
+        || config.cname.is_some()
+        || config.ptr.is_some()
+        || config.origins.is_some();
+    (!a && !aaaa && !other).then(|| "neither A nor AAAA nor any other record is enabled".into())
+}
+
+/// Warnings about the parsed config, by the subdomain (or global table) they
+/// apply to
+fn lint_config(config: &FileConfig) -> Vec<(String, String)> {
+    let global = &config.subdomains_config;
+    let mut warnings: Vec<(String, String)> = ignored_global(global)
+        .into_iter()
+        .map(|warning| ("[subdomains]".to_string(), warning))
+        .collect();
+
+    let mut subdomains: Vec<_> = config.subdomains.iter().collect();
+    subdomains.sort_by_key(|(subdomain, _)| *subdomain);
+    for (subdomain, config) in subdomains {
+        let subdomain_warnings = overridden_disable(config, global)
+            .into_iter()
+            .chain(proxied_ttl(config, global))
+            .chain(ignored_address_types(config))
+            .chain(no_records(config, global));
+        warnings.extend(subdomain_warnings.map(|warning| (format!("{subdomain:?}"), warning)));
+    }
+    warnings
+}
+
+/// Prints the shadowed and contradictory settings of the config without
+/// making any API request. Returns whether there were none
+pub async fn lint(args: &Args) -> Result<bool> {
+    let config = get_file_config_or_default(args).await?;
+    let warnings = lint_config(&config);
+    for (name, warning) in &warnings {
+        println!("{name}: {warning}");
+    }
+    Ok(warnings.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(config: &str) -> FileConfig {
+        toml::from_str(config).unwrap()
+    }
+
+    fn subdomain(config: &str) -> SubdomainsConfig {
+        toml::from_str(config).unwrap()
+    }
+
+    #[test]
+    fn ignored_globals() {
+        let global =
+            subdomain("api_token = \"token\"\nns = [\"ns1.other.tld\"]\ncname = \"a.tld\"");
+        assert_eq!(
+            ignored_global(&global),
+            [
+                "api_token is only honored per subdomain and is ignored in [subdomains]",
+                "ns is only honored per subdomain and is ignored in [subdomains]",
+                "cname is only honored per subdomain and is ignored in [subdomains]",
+            ]
+        );
+        assert!(ignored_global(&subdomain("a = true\nttl = 300")).is_empty());
+    }
+
+    #[test]
+    fn overridden_disables() {
+        let global = subdomain("a = false\naaaa = false");
+        assert_eq!(
+            overridden_disable(&subdomain("a = true\naaaa = true"), &global),
+            [
+                "a = true overrides a = false in [subdomains]",
+                "aaaa = true overrides aaaa = false in [subdomains]",
+            ]
+        );
+        assert!(overridden_disable(&subdomain("a = false"), &global).is_empty());
+        // Enabling what isn't disabled globally overrides nothing
+        assert!(overridden_disable(&subdomain("aaaa = true"), &subdomain("")).is_empty());
+    }
+
+    #[test]
+    fn proxied_ttls() {
+        let global = SubdomainsConfig::default();
+        let warning = Some("ttl = 300 is ignored while the record is proxied".to_string());
+        // Records are proxied by default
+        assert_eq!(proxied_ttl(&subdomain("ttl = 300"), &global), warning);
+        assert_eq!(
+            proxied_ttl(&subdomain("ttl = 300"), &subdomain("proxied = true")),
+            warning
+        );
+        let schedule = "proxied = false\nttl = 300\nproxied_schedule = \"08:00-18:00\"";
+        assert_eq!(proxied_ttl(&subdomain(schedule), &global), warning);
+
+        for config in [
+            "ttl = 300\nproxied = false",
+            "ttl = 1",
+            "ttl = \"keep\"",
+            "",
+        ] {
+            assert_eq!(proxied_ttl(&subdomain(config), &global), None, "{config}");
+        }
+        assert_eq!(
+            proxied_ttl(&subdomain("ttl = 300"), &subdomain("proxied = false")),
+            None
+        );
+    }
+
+    #[test]
+    fn ignored_address_type() {
+        assert_eq!(
+            ignored_address_types(&subdomain("cname = \"a.tld\"\na = true\naaaa = false")),
+            ["a = true is ignored because cname is set"]
+        );
+        let origins = "origins = [{ ip = \"203.0.113.10\", health_check = { tcp = 443 } }]";
+        assert_eq!(
+            ignored_address_types(&subdomain(&format!("{origins}\naaaa = true"))),
+            ["aaaa = true is ignored because origins is set"]
+        );
+        assert_eq!(
+            ignored_address_types(&subdomain("ns = [\"ns1.other.tld\"]\na = true")),
+            ["a = true is ignored because ns is set"]
+        );
+        assert!(ignored_address_types(&subdomain("a = true\naaaa = true")).is_empty());
+        assert!(ignored_address_types(&subdomain("cname = \"a.tld\"")).is_empty());
+    }
+
+    #[test]
+    fn without_records() {
+        let global = SubdomainsConfig::default();
+        let warning = Some("neither A nor AAAA nor any other record is enabled".to_string());
+        assert_eq!(no_records(&subdomain("a = false"), &global), warning);
+        assert_eq!(
+            no_records(&subdomain(""), &subdomain("a = false\naaaa = false")),
+            warning
+        );
+        assert_eq!(no_records(&subdomain(""), &global), None);
+        assert_eq!(
+            no_records(&subdomain("a = false\naaaa = true"), &global),
+            None
+        );
+        assert_eq!(
+            no_records(&subdomain("a = false\nptr = \"home.example.tld\""), &global),
+            None
+        );
+        assert_eq!(
+            no_records(&subdomain("a = false"), &subdomain("aaaa = true")),
+            None
+        );
+    }
+
+    #[test]
+    fn config_warnings() {
+        let config = parse(
+            r#"
+            [subdomains]
+            a = false
+            cname = "a.tld"
+
+            [subdomain.www]
+            cname = "example.github.io"
+            a = true
+
+            [subdomain.home]
+            ttl = 300
+
+            [subdomain.vpn]
+            aaaa = true
+            proxied = false
+            ttl = 300
+            "#,
+        );
+        let warnings: Vec<_> = lint_config(&config)
+            .into_iter()
+            .map(|(name, warning)| format!("{name}: {warning}"))
+            .collect();
+        assert_eq!(
+            warnings,
+            [
+                "[subdomains]: cname is only honored per subdomain and is ignored in [subdomains]",
+                "\"home\": ttl = 300 is ignored while the record is proxied",
+                "\"home\": neither A nor AAAA nor any other record is enabled",
+                "\"www\": a = true overrides a = false in [subdomains]",
+                "\"www\": a = true is ignored because cname is set",
+            ]
+        );
+        assert!(lint_config(&parse("[subdomain.home]\n")).is_empty());
+    }
+}
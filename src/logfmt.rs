@@ -0,0 +1,53 @@
+use std::borrow::Cow;
+use std::io::{self, Write};
+
+use env_logger::fmt::Formatter;
+use log::Record;
+
+use crate::client::RecordOutcome;
+
+/// Target of the log records of the run outcomes, whose messages are already
+/// `key=value` pairs
+pub const OUTCOME_TARGET: &str = "cf_ddns::outcome";
+
+/// `value`, quoted if it is empty or has spaces, quotes or `=`
+fn value(value: &str) -> Cow<'_, str> {
+    if !value.is_empty() && !value.contains(|c: char| c.is_whitespace() || c == '"' || c == '=') {
+        return Cow::Borrowed(value);
+    }
+    let escaped = value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n");
+    Cow::Owned(format!("\"{escaped}\""))
+}
+
+/// `env_logger` format writing each record as `key=value` pairs
+pub fn format(buf: &mut Formatter, record: &Record) -> io::Result<()> {
+    let ts = buf.timestamp();
+    let level = record.level().as_str().to_ascii_lowercase();
+    if record.target() == OUTCOME_TARGET {
+        return writeln!(buf, "ts={ts} level={level} {}", record.args());
+    }
+    writeln!(
+        buf,
+        "ts={ts} level={level} target={} msg={}",
+        value(record.target()),
+        value(&record.args().to_string())
+    )
+}
+
+/// Fields of a committed record, logged to `OUTCOME_TARGET`
+pub fn outcome(outcome: &RecordOutcome) -> String {
+    let mut fields = format!(
+        "fqdn={} type={} action={}",
+        value(&outcome.fqdn),
+        outcome.type_,
+        outcome.action.as_str()
+    );
+    if let Some(old) = &outcome.old_content {
+        fields += &format!(" old={}", value(old));
+    }
+    fields += &format!(" new={}", value(&outcome.content));
+    fields
+}
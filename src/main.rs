@@ -1,34 +1,526 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::future::Future;
 use std::process::ExitCode;
+use std::time::Duration;
 
-use clap::Parser;
+use clap::{CommandFactory, Parser};
+use color_eyre::eyre::WrapErr;
 use color_eyre::Result;
-use log::error;
+use log::{error, info, warn};
 
+mod changelog;
 mod client;
 mod config;
+mod doctor;
+mod exit;
+mod lint;
+mod logfmt;
+mod marker;
+mod propagation;
+mod ptr;
+mod report;
+mod snapshot;
+mod statsd;
+mod trigger;
 mod util;
+mod version;
+mod watch;
 
 use crate::client::*;
 use crate::config::*;
+use crate::exit::{ErrorKind, ExitMode, ExitStatus};
+use crate::util::FixedIps;
+
+/// Results of committing all the subdomains once
+struct RunResult {
+    status: ExitStatus,
+    outcomes: Vec<RecordOutcome>,
+    /// Subdomains that failed to be committed
+    failed: Vec<String>,
+}
+
+/// Commits all the subdomains once and runs the post-update steps
+async fn run(client: &mut Client) -> RunResult {
+    client.start_run();
+    client.clear_detected_ips();
+    if client.config.refresh_zones {
+        client.clear_zones();
+    }
+
+    // Holding the Rc instead of cloning the subdomains lets the client be
+    // borrowed mutably while iterating
+    let config = client.config.clone();
+    let subdomains = &config.subdomains;
+    let mut jobs = Vec::new();
+    for (subdomain, config) in subdomains {
+        for (zone, config) in config.per_zone() {
+            let name = match zone {
+                Some(zone) => format!("{subdomain} ({zone})"),
+                None => subdomain.clone(),
+            };
+            jobs.push((subdomain, name, config));
+        }
+    }
+    let total = jobs.len();
+
+    // Zones are checked up front so one that can't be accessed fails all of
+    // its subdomains with a single error
+    let mut zone_errors = HashMap::new();
+    for (_, _, config) in &jobs {
+        if let Entry::Vacant(entry) = zone_errors.entry(client.zone_key(config)) {
+            entry.insert(client.check_zone(config).await.err());
+        }
+    }
+
+    let mut outcomes = Vec::new();
+    let mut failed = Vec::new();
+    let mut failures = Vec::new();
+    for (key, error) in &zone_errors {
+        let Some(e) = error else {
+            continue;
+        };
+        let names: Vec<&str> = jobs
+            .iter()
+            .filter(|(_, _, config)| client.zone_key(config) == *key)
+            .map(|(_, name, _)| name.as_str())
+            .collect();
+        error!(
+            "Can't access zone {}, skipping subdomains {}: {e:?}",
+            key.1,
+            names.join(", ")
+        );
+        for name in names {
+            failed.push(name.to_string());
+            failures.push(ExitStatus::from_error(e));
+        }
+    }
+
+    for (subdomain, name, config) in &jobs {
+        if let Some(Some(_)) = zone_errors.get(&client.zone_key(config)) {
+            continue;
+        }
+        // Failing to commit to one zone doesn't stop the others
+        match client.commit_record(subdomain, config).await {
+            Ok(record_outcomes) => outcomes.extend(record_outcomes),
+            Err(e) => {
+                error!("Failed to commit record for subdomain {name:?}: {e:?}");
+                failed.push(name.clone());
+                failures.push(ExitStatus::from_error(&e));
+            }
+        }
+    }
+    let status = ExitStatus::from_run(total, &failures);
+
+    if client.config.log_format == LogFormat::Logfmt {
+        for outcome in &outcomes {
+            info!(target: logfmt::OUTCOME_TARGET, "{}", logfmt::outcome(outcome));
+        }
+    }
+
+    let summary = report::summary(&outcomes);
+    if !summary.is_empty() {
+        info!("Changes:\n{}", summary.trim_end());
+    }
+
+    if client.config.dry_run {
+        return RunResult {
+            status,
+            outcomes,
+            failed,
+        };
+    }
+
+    if let Some(path) = &client.config.changelog {
+        if let Err(e) = changelog::append(path, &outcomes) {
+            warn!("Failed to write changelog: {e:?}");
+        }
+    }
+
+    if let Some(timeout) = client.config.wait_propagation {
+        propagation::wait_for_propagation(
+            &client.config.propagation_resolvers,
+            client.config.propagation_quorum,
+            &outcomes,
+            timeout,
+        )
+        .await;
+    }
+
+    if let Some(path) = &client.config.write_ip_file {
+        let ips: String = client
+            .detected_ips()
+            .map(|(_, ip)| format!("{ip}\n"))
+            .collect();
+        if !ips.is_empty() {
+            if let Err(e) = util::write_atomic(path, &ips) {
+                warn!("Failed to write detected IPs: {e:?}");
+            }
+        }
+    }
+
+    if let Some(path) = &client.config.write_record_id {
+        let ids: String = outcomes
+            .iter()
+            .filter(|outcome| outcome.action != Action::Deleted)
+            .filter_map(|outcome| {
+                let id = outcome.id.as_ref()?;
+                Some(format!("{},{},{id}\n", outcome.fqdn, outcome.type_))
+            })
+            .collect();
+        if path.as_os_str() == "-" {
+            print!("{ids}");
+        } else if let Err(e) = util::write_atomic(path, &ids) {
+            warn!("Failed to write record ids: {e:?}");
+        }
+    }
+
+    if let Some(statsd_addr) = &client.config.statsd_addr {
+        if let Err(e) = statsd::push(statsd_addr, &outcomes, &failed, client.detected_ips()) {
+            warn!("Failed to push metrics: {e:?}");
+        }
+    }
+
+    if let Some(path) = &client.config.report_socket {
+        if let Err(e) = report::send(path, &outcomes, &failed, client.detected_ips()).await {
+            warn!("Failed to send run report: {e:?}");
+        }
+    }
+
+    RunResult {
+        status,
+        outcomes,
+        failed,
+    }
+}
+
+/// Whether the disable file, if configured, exists
+fn is_disabled(config: &Config) -> bool {
+    let Some(path) = &config.disable_file else {
+        return false;
+    };
+    let disabled = path.exists();
+    if disabled {
+        info!("Disable file {path:?} exists, not touching any record");
+    }
+    disabled
+}
+
+/// Whether the trigger file, if configured, signals that the records should be
+/// updated
+fn is_triggered(config: &Config) -> Result<bool> {
+    let Some(path) = &config.trigger_file else {
+        return Ok(true);
+    };
+    let triggered = trigger::is_triggered(path, config.trigger_mode)?;
+    if !triggered {
+        info!("Trigger file {path:?} not set, skipping update");
+    }
+    Ok(triggered)
+}
+
+/// Marks the trigger file as handled if the run succeeded, so the next run
+/// waits for it to be set again
+fn acknowledge_trigger(config: &Config, result: &RunResult) {
+    let Some(path) = &config.trigger_file else {
+        return;
+    };
+    if result.status != ExitStatus::Success || config.dry_run {
+        return;
+    }
+    if let Err(e) = trigger::acknowledge(path, config.trigger_mode) {
+        warn!("Failed to acknowledge trigger file: {e:?}");
+    }
+}
+
+/// Sets the records of every subdomain with an `on_shutdown_ip` to it. Record
+/// types without an address of their family in `on_shutdown_ip` are left alone
+async fn reset_records(client: &mut Client) -> bool {
+    let global = client.config.clone();
+    let global_ips = global.subdomains_config.on_shutdown_ip.as_ref();
+    let mut success = true;
+    for (subdomain, config) in &global.subdomains {
+        let Some(ips) = config.on_shutdown_ip.as_ref().or(global_ips) else {
+            continue;
+        };
+
+        let has_v4 = ips.iter().any(|ip| ip.is_ipv4());
+        let has_v6 = ips.iter().any(|ip| ip.is_ipv6());
+        let config = SubdomainsConfig {
+            a: if has_v4 { config.a } else { Some(false) },
+            aaaa: if has_v6 { config.aaaa } else { Some(false) },
+            ns: None,
+            cname: None,
+            origins: None,
+            ..config.clone()
+        };
+        client.set_ip_provider(Some(Box::new(FixedIps(ips.clone()))));
+        for (zone, config) in config.per_zone() {
+            if let Err(e) = client.commit_record(subdomain, &config).await {
+                let zone = zone.map(|zone| format!(" ({zone})")).unwrap_or_default();
+                error!("Failed to reset record for subdomain {subdomain:?}{zone}: {e:?}");
+                success = false;
+            }
+        }
+    }
+    client.set_ip_provider(None);
+    success
+}
+
+/// Resolves once the process is asked to stop. Handlers are installed right
+/// away so signals received during a run aren't lost
+#[cfg(unix)]
+fn shutdown_signal() -> Result<impl Future<Output = ()>> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    Ok(async move {
+        tokio::select! {
+            _ = interrupt.recv() => {}
+            _ = terminate.recv() => {}
+        }
+    })
+}
+
+#[cfg(not(unix))]
+fn shutdown_signal() -> Result<impl Future<Output = ()>> {
+    Ok(async {
+        let _ = tokio::signal::ctrl_c().await;
+    })
+}
 
 #[tokio::main]
-async fn main() -> Result<ExitCode> {
+async fn main() -> ExitCode {
+    let args = match Args::try_parse() {
+        Ok(args) => args,
+        // clap exits with 2 on invalid arguments, which is the drift of
+        // --diff-only
+        Err(e) if e.use_stderr() && std::env::args_os().any(|arg| arg == "--diff-only") => {
+            let _ = e.print();
+            return ExitCode::FAILURE;
+        }
+        Err(e) => e.exit(),
+    };
+    let mode = ExitMode {
+        diff_only: args.diff_only,
+        soft_fail: args.soft_fail,
+    };
+    match try_main(args, mode).await {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("Error: {e:?}");
+            mode.code(ExitStatus::from_error(&e)).into()
+        }
+    }
+}
+
+async fn try_main(mut args: Args, mode: ExitMode) -> Result<ExitCode> {
     color_eyre::install()?;
 
-    let args = Args::parse();
+    if args.print_version_info {
+        println!("{}", version::info());
+        return Ok(ExitCode::SUCCESS);
+    }
+    if let Some(shell) = args.completion {
+        let name = env!("CARGO_PKG_NAME");
+        clap_complete::generate(shell, &mut Args::command(), name, &mut std::io::stdout());
+        return Ok(ExitCode::SUCCESS);
+    }
+    let command = args.command.take();
+    let preflight = args.preflight;
 
-    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    let mut logger =
+        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"));
+    if args.trace_api {
+        logger.filter_module("cf_ddns::api", log::LevelFilter::Trace);
+    }
+    if args.log_format == LogFormat::Logfmt {
+        logger.format(logfmt::format);
+    }
+    logger.init();
 
-    let config = Config::new(args)?;
+    if command == Some(Command::Doctor) {
+        let passed = doctor::doctor(args).await;
+        return Ok((!passed as u8).into());
+    }
+
+    if command == Some(Command::Lint) {
+        let clean = lint::lint(&args).await.wrap_err(ErrorKind::Config)?;
+        return Ok((!clean as u8).into());
+    }
+
+    if command == Some(Command::SupportedTypes) {
+        for (type_, keys) in SUPPORTED_TYPES {
+            println!("{type_:<6} {keys}");
+        }
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    let config = Config::new(args).await.wrap_err(ErrorKind::Config)?;
     let mut client = Client::new(config)?;
 
-    let mut failed = false;
-    for (subdomain, config) in &client.config.subdomains.clone() {
-        if let Err(e) = client.commit_record(subdomain, config).await {
-            error!("Failed to commit record for subdomain {subdomain:?}: {e:?}");
-            failed = true;
+    if command == Some(Command::Export) {
+        print!("{}", client.export().await?);
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    if let Some(Command::Status { raw }) = command {
+        print!("{}", client.status(raw).await?);
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    if command == Some(Command::Fqdns) {
+        print!("{}", client.fqdns().await?);
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    if command == Some(Command::Snapshot) {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&client.snapshot().await?)?
+        );
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    if let Some(Command::Diff { against }) = &command {
+        let old = std::fs::read_to_string(against)
+            .wrap_err_with(|| format!("Failed to read snapshot {against:?}"))?;
+        let old: snapshot::Snapshot = serde_json::from_str(&old)
+            .wrap_err_with(|| format!("Failed to parse snapshot {against:?}"))?;
+        let new = client.snapshot().await?;
+        let diffs = snapshot::diff(&old, &new);
+        for diff in &diffs {
+            println!("{diff}");
+        }
+        return Ok((!diffs.is_empty() as u8).into());
+    }
+
+    if preflight {
+        let mut checklist = doctor::Checklist::default();
+        client.preflight(&mut checklist).await;
+        return Ok((!checklist.passed() as u8).into());
+    }
+
+    let Some(interval) = client.config.interval else {
+        if is_disabled(&client.config) || !is_triggered(&client.config)? {
+            return Ok(ExitCode::SUCCESS);
+        }
+        let result = match client.config.max_runtime {
+            Some(max_runtime) => match tokio::time::timeout(max_runtime, run(&mut client)).await {
+                Ok(result) => result,
+                Err(_) => {
+                    error!("Run didn't finish within {}s", max_runtime.as_secs());
+                    RunResult {
+                        status: ExitStatus::TotalFailure,
+                        outcomes: Vec::new(),
+                        failed: client.config.subdomains.keys().cloned().collect(),
+                    }
+                }
+            },
+            None => run(&mut client).await,
+        };
+        acknowledge_trigger(&client.config, &result);
+        if client.config.output == OutputFormat::Json {
+            println!(
+                "{}",
+                report::report(&result.outcomes, &result.failed, client.detected_ips())
+            );
+        }
+        if let Some(command) = &client.config.then {
+            return report::exec(
+                command,
+                &result.outcomes,
+                &result.failed,
+                client.detected_ips(),
+            );
+        }
+        let changed = result
+            .outcomes
+            .iter()
+            .any(|outcome| outcome.action != Action::Unchanged);
+        if let (true, ExitStatus::Success, true) = (client.config.diff_only, result.status, changed)
+        {
+            return Ok(ExitCode::from(exit::DRIFT));
+        }
+        if let (Some(code), ExitStatus::Success, true) =
+            (client.config.changed_exit_code, result.status, changed)
+        {
+            return Ok(ExitCode::from(code));
+        }
+        return Ok(mode.code(result.status).into());
+    };
+
+    let mut watcher = None;
+    if client.config.watch_interface {
+        match watch::AddressWatcher::new() {
+            Ok(w) => watcher = Some(w),
+            Err(e) => warn!("Can't watch network interfaces, polling instead: {e:?}"),
         }
     }
 
-    Ok((failed as u8).into())
+    let shutdown = shutdown_signal()?;
+    tokio::pin!(shutdown);
+    // Wait before the next run after runs where everything failed, e.g. while
+    // offline. Separate from the retries of each API request. Unused while
+    // watching the interfaces, where runs only follow address changes
+    let mut backoff: Option<Duration> = None;
+    loop {
+        let triggered = match is_disabled(&client.config) {
+            true => Ok(false),
+            false => is_triggered(&client.config),
+        };
+        match triggered {
+            Ok(true) => {
+                let result = run(&mut client).await;
+                acknowledge_trigger(&client.config, &result);
+                match result.status {
+                    ExitStatus::Success | ExitStatus::PartialFailure => {
+                        if backoff.take().is_some() {
+                            info!(
+                                "Run succeeded, back to the {}s interval",
+                                interval.as_secs()
+                            );
+                        }
+                    }
+                    ExitStatus::TotalFailure | ExitStatus::Detection => {
+                        let next = backoff.unwrap_or(interval) * 2;
+                        let next = next.min(client.config.max_backoff.max(interval));
+                        warn!("Run failed, backing off: next run in {}s", next.as_secs());
+                        backoff = Some(next);
+                    }
+                    // Waiting longer won't fix the config or the credentials
+                    ExitStatus::Config | ExitStatus::Auth => {}
+                }
+            }
+            Ok(false) => {}
+            Err(e) => error!("Failed to check trigger file: {e:?}"),
+        }
+        let next_run = async {
+            if let Some(w) = &mut watcher {
+                match w.changed().await {
+                    Ok(()) => {
+                        info!("Network addresses changed");
+                        return;
+                    }
+                    Err(e) => warn!("Stopped watching network interfaces, polling instead: {e:?}"),
+                }
+                watcher = None;
+            }
+            tokio::time::sleep(backoff.unwrap_or(interval)).await
+        };
+        tokio::select! {
+            _ = next_run => {}
+            _ = &mut shutdown => break,
+        }
+    }
+
+    info!("Shutting down");
+    if client.config.reset_on_shutdown && !is_disabled(&client.config) {
+        let success = reset_records(&mut client).await;
+        if !success && !client.config.soft_fail {
+            return Ok(ExitCode::FAILURE);
+        }
+    }
+    Ok(ExitCode::SUCCESS)
 }
@@ -0,0 +1,113 @@
+use cloudflare::endpoints::dns::DnsRecord;
+use cloudflare::framework::endpoint::{Endpoint, Method};
+use cloudflare::framework::response::ApiResult;
+use serde::{Deserialize, Serialize};
+
+/// Comment stamped on the records cf-ddns manages when --require-marker is
+/// set
+pub const MARKER: &str = "managed by cf-ddns";
+
+/// Fields of a record the cloudflare crate's `DnsRecord` doesn't expose, so
+/// they are listed and set with their own endpoints
+#[derive(Deserialize, Debug)]
+pub struct RecordExtra {
+    pub id: String,
+    pub comment: Option<String>,
+    pub priority: Option<u16>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+impl RecordExtra {
+    pub fn has_marker(&self) -> bool {
+        self.comment
+            .as_deref()
+            .is_some_and(|comment| comment.contains(MARKER))
+    }
+}
+
+impl ApiResult for RecordExtra {}
+
+#[derive(Deserialize, Debug)]
+#[serde(transparent)]
+pub struct RecordExtras(pub Vec<RecordExtra>);
+
+impl ApiResult for RecordExtras {}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct ListRecordExtrasParams {
+    name: String,
+    per_page: u32,
+}
+
+/// Extra fields of the records named `name`
+pub struct ListRecordExtras<'a> {
+    pub zone_identifier: &'a str,
+    pub name: &'a str,
+}
+
+impl Endpoint<RecordExtras, ListRecordExtrasParams> for ListRecordExtras<'_> {
+    fn method(&self) -> Method {
+        Method::Get
+    }
+    fn path(&self) -> String {
+        format!("zones/{}/dns_records", self.zone_identifier)
+    }
+    fn query(&self) -> Option<ListRecordExtrasParams> {
+        Some(ListRecordExtrasParams {
+            name: self.name.to_string(),
+            per_page: 100,
+        })
+    }
+}
+
+/// Record `identifier`, which the cloudflare crate has no endpoint to get
+pub struct GetDnsRecord<'a> {
+    pub zone_identifier: &'a str,
+    pub identifier: &'a str,
+}
+
+impl Endpoint<DnsRecord> for GetDnsRecord<'_> {
+    fn method(&self) -> Method {
+        Method::Get
+    }
+    fn path(&self) -> String {
+        format!(
+            "zones/{}/dns_records/{}",
+            self.zone_identifier, self.identifier
+        )
+    }
+}
+
+/// Extra fields to set on a record. Unset ones are left as they are
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct PatchRecordParams<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub comment: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub priority: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tags: Option<&'a [String]>,
+}
+
+/// Replaces extra fields of a record, leaving the rest of it untouched
+pub struct PatchRecord<'a> {
+    pub zone_identifier: &'a str,
+    pub identifier: &'a str,
+    pub params: PatchRecordParams<'a>,
+}
+
+impl<'a> Endpoint<RecordExtra, (), PatchRecordParams<'a>> for PatchRecord<'a> {
+    fn method(&self) -> Method {
+        Method::Patch
+    }
+    fn path(&self) -> String {
+        format!(
+            "zones/{}/dns_records/{}",
+            self.zone_identifier, self.identifier
+        )
+    }
+    fn body(&self) -> Option<PatchRecordParams<'a>> {
+        Some(self.params.clone())
+    }
+}
@@ -0,0 +1,148 @@
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use color_eyre::Result;
+use hickory_resolver::config::{NameServerConfigGroup, ResolverConfig, ResolverOpts};
+use hickory_resolver::TokioAsyncResolver;
+use log::{debug, info, warn};
+
+use crate::client::{Action, RecordOutcome};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+async fn is_visible(resolver: &TokioAsyncResolver, outcome: &RecordOutcome) -> Result<bool> {
+    let fqdn = &outcome.fqdn;
+    let ips: Vec<String> = match outcome.type_ {
+        "A" => resolver
+            .ipv4_lookup(fqdn.as_str())
+            .await?
+            .iter()
+            .map(|a| a.0.to_string())
+            .collect(),
+        _ => resolver
+            .ipv6_lookup(fqdn.as_str())
+            .await?
+            .iter()
+            .map(|aaaa| aaaa.0.to_string())
+            .collect(),
+    };
+    debug!(
+        "{fqdn}: resolver returned {ips:?} for {} record",
+        outcome.type_
+    );
+
+    Ok(ips.contains(&outcome.content))
+}
+
+fn resolver(ip: IpAddr) -> TokioAsyncResolver {
+    let mut opts = ResolverOpts::default();
+    // Every poll has to reach the resolver
+    opts.cache_size = 0;
+    let config = ResolverConfig::from_parts(
+        None,
+        vec![],
+        NameServerConfigGroup::from_ips_clear(&[ip], 53, true),
+    );
+    TokioAsyncResolver::tokio(config, opts)
+}
+
+/// Polls `resolvers` until every created/updated record resolves to its new IP
+/// on at least `quorum` of them or `timeout` elapses. Proxied records are
+/// skipped since they resolve to Cloudflare's addresses
+pub async fn wait_for_propagation(
+    resolvers: &[IpAddr],
+    quorum: usize,
+    outcomes: &[RecordOutcome],
+    timeout: Duration,
+) {
+    // Each pending record with the resolvers it's already visible on
+    let mut pending: Vec<(&RecordOutcome, HashSet<IpAddr>)> = outcomes
+        .iter()
+        .filter(|outcome| matches!(outcome.action, Action::Created | Action::Updated))
+        .filter(|outcome| matches!(outcome.type_, "A" | "AAAA"))
+        .filter(|outcome| {
+            if outcome.proxied {
+                info!("{}: proxied, not waiting for propagation", outcome.fqdn);
+            }
+            !outcome.proxied
+        })
+        .map(|outcome| (outcome, HashSet::new()))
+        .collect();
+
+    if pending.is_empty() {
+        return;
+    }
+
+    let dns: Vec<(IpAddr, TokioAsyncResolver)> =
+        resolvers.iter().map(|&ip| (ip, resolver(ip))).collect();
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        let mut still_pending = Vec::new();
+        for (outcome, mut visible_on) in pending {
+            // Resolvers that already returned the new IP aren't asked again
+            let lookups = dns
+                .iter()
+                .filter(|(ip, _)| !visible_on.contains(ip))
+                .map(|(ip, dns)| async move { (*ip, is_visible(dns, outcome).await) });
+            for (ip, result) in futures::future::join_all(lookups).await {
+                match result {
+                    Ok(true) => {
+                        debug!(
+                            "{}: {} record propagated to {ip}",
+                            outcome.fqdn, outcome.type_
+                        );
+                        visible_on.insert(ip);
+                    }
+                    Ok(false) => {}
+                    Err(e) => debug!("{}: lookup on {ip} failed: {e}", outcome.fqdn),
+                }
+            }
+
+            if visible_on.len() >= quorum {
+                info!(
+                    "{}: {} record propagated to {}/{} resolvers",
+                    outcome.fqdn,
+                    outcome.type_,
+                    visible_on.len(),
+                    dns.len()
+                );
+            } else {
+                still_pending.push((outcome, visible_on));
+            }
+        }
+        pending = still_pending;
+
+        if pending.is_empty() {
+            return;
+        }
+        if Instant::now() + POLL_INTERVAL > deadline {
+            break;
+        }
+        info!(
+            "Waiting for {} record(s) to propagate to {quorum}/{} resolvers",
+            pending.len(),
+            dns.len()
+        );
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+
+    for (outcome, visible_on) in pending {
+        let missing: Vec<String> = resolvers
+            .iter()
+            .filter(|ip| !visible_on.contains(ip))
+            .map(IpAddr::to_string)
+            .collect();
+        warn!(
+            "{}: {} record propagated to {}/{} resolvers within {}s, needed {quorum}. \
+            Not visible on {}",
+            outcome.fqdn,
+            outcome.type_,
+            visible_on.len(),
+            dns.len(),
+            timeout.as_secs(),
+            missing.join(", ")
+        );
+    }
+}
@@ -0,0 +1,146 @@
+use std::net::IpAddr;
+
+use cloudflare::framework::endpoint::{Endpoint, Method};
+use cloudflare::framework::response::ApiResult;
+use serde::{Deserialize, Serialize};
+
+/// PTR record. The cloudflare crate's `DnsContent` doesn't support them and
+/// listing them with `dns::ListDnsRecords` would fail to deserialize, so
+/// they have their own endpoints
+#[derive(Deserialize, Debug)]
+pub struct PtrRecord {
+    pub id: String,
+    pub name: String,
+    pub content: String,
+    pub ttl: u32,
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+impl ApiResult for PtrRecord {}
+
+#[derive(Deserialize, Debug)]
+#[serde(transparent)]
+pub struct PtrRecords(pub Vec<PtrRecord>);
+
+impl ApiResult for PtrRecords {}
+
+/// Records per page of `ListPtrRecords`
+pub const PER_PAGE: u32 = 100;
+
+#[derive(Serialize, Clone, Debug)]
+pub struct ListPtrRecordsParams {
+    #[serde(rename = "type")]
+    type_: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content: Option<String>,
+    page: u32,
+    per_page: u32,
+}
+
+/// Page `page` of the PTR records of a zone, only those named `name` and/or
+/// pointing at `content` if set
+pub struct ListPtrRecords<'a> {
+    pub zone_identifier: &'a str,
+    pub name: Option<&'a str>,
+    pub content: Option<&'a str>,
+    pub page: u32,
+}
+
+impl Endpoint<PtrRecords, ListPtrRecordsParams> for ListPtrRecords<'_> {
+    fn method(&self) -> Method {
+        Method::Get
+    }
+    fn path(&self) -> String {
+        format!("zones/{}/dns_records", self.zone_identifier)
+    }
+    fn query(&self) -> Option<ListPtrRecordsParams> {
+        Some(ListPtrRecordsParams {
+            type_: "PTR",
+            name: self.name.map(str::to_string),
+            content: self.content.map(str::to_string),
+            page: self.page,
+            per_page: PER_PAGE,
+        })
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct PtrRecordParams<'a> {
+    #[serde(rename = "type")]
+    type_: &'static str,
+    name: &'a str,
+    content: &'a str,
+    ttl: u32,
+    tags: &'a [String],
+}
+
+impl<'a> PtrRecordParams<'a> {
+    pub fn new(name: &'a str, content: &'a str, ttl: u32, tags: &'a [String]) -> Self {
+        PtrRecordParams {
+            type_: "PTR",
+            name,
+            content,
+            ttl,
+            tags,
+        }
+    }
+}
+
+pub struct CreatePtrRecord<'a> {
+    pub zone_identifier: &'a str,
+    pub params: PtrRecordParams<'a>,
+}
+
+impl<'a> Endpoint<PtrRecord, (), PtrRecordParams<'a>> for CreatePtrRecord<'a> {
+    fn method(&self) -> Method {
+        Method::Post
+    }
+    fn path(&self) -> String {
+        format!("zones/{}/dns_records", self.zone_identifier)
+    }
+    fn body(&self) -> Option<PtrRecordParams<'a>> {
+        Some(self.params.clone())
+    }
+}
+
+pub struct UpdatePtrRecord<'a> {
+    pub zone_identifier: &'a str,
+    pub identifier: &'a str,
+    pub params: PtrRecordParams<'a>,
+}
+
+impl<'a> Endpoint<PtrRecord, (), PtrRecordParams<'a>> for UpdatePtrRecord<'a> {
+    fn method(&self) -> Method {
+        Method::Put
+    }
+    fn path(&self) -> String {
+        format!(
+            "zones/{}/dns_records/{}",
+            self.zone_identifier, self.identifier
+        )
+    }
+    fn body(&self) -> Option<PtrRecordParams<'a>> {
+        Some(self.params.clone())
+    }
+}
+
+/// Name of the PTR record of `ip`, e.g. `4.3.2.1.in-addr.arpa` for 1.2.3.4
+pub fn reverse_name(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, d] = ip.octets();
+            format!("{d}.{c}.{b}.{a}.in-addr.arpa")
+        }
+        IpAddr::V6(ip) => {
+            let nibbles: Vec<String> = format!("{:032x}", u128::from(ip))
+                .chars()
+                .rev()
+                .map(String::from)
+                .collect();
+            format!("{}.ip6.arpa", nibbles.join("."))
+        }
+    }
+}
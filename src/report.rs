@@ -0,0 +1,141 @@
+use std::path::Path;
+use std::process::{self, ExitCode};
+
+use color_eyre::eyre::Context;
+use color_eyre::Result;
+use log::info;
+use serde_json::json;
+
+use crate::client::{Action, RecordOutcome};
+use crate::util::IP;
+
+/// One line per created, updated or deleted record, with the fields that
+/// changed for updated ones, e.g. `home.example.com A: updated (content, ttl)`
+pub fn summary(outcomes: &[RecordOutcome]) -> String {
+    outcomes
+        .iter()
+        .filter(|outcome| outcome.action != Action::Unchanged)
+        .map(|outcome| {
+            let mut line = format!(
+                "{} {}: {}",
+                outcome.fqdn,
+                outcome.type_,
+                outcome.action.as_str()
+            );
+            if outcome.action == Action::Updated {
+                line += &format!(" ({})", outcome.changes.names().join(", "));
+            }
+            line + "\n"
+        })
+        .collect()
+}
+
+/// JSON summary of a run
+pub fn report<'a>(
+    outcomes: &[RecordOutcome],
+    failed: &[String],
+    ips: impl Iterator<Item = (IP, &'a str)>,
+) -> String {
+    let records: Vec<_> = outcomes
+        .iter()
+        .map(|outcome| {
+            json!({
+                "fqdn": outcome.fqdn,
+                "type": outcome.type_,
+                "action": outcome.action.as_str(),
+                "old_content": outcome.old_content,
+                "content": outcome.content,
+                "proxied": outcome.proxied,
+                "changed": outcome.changes.names(),
+            })
+        })
+        .collect();
+    let ips: Vec<_> = ips
+        .map(|(version, ip)| json!({ "version": version.to_string(), "ip": ip }))
+        .collect();
+
+    json!({
+        "timestamp": chrono::Utc::now().to_rfc3339(),
+        "records": records,
+        "failed": failed,
+        "ips": ips,
+    })
+    .to_string()
+}
+
+/// Writes the report of a run to the Unix socket at `path` and closes the
+/// connection
+#[cfg(unix)]
+pub async fn send<'a>(
+    path: &Path,
+    outcomes: &[RecordOutcome],
+    failed: &[String],
+    ips: impl Iterator<Item = (IP, &'a str)>,
+) -> Result<()> {
+    use tokio::io::AsyncWriteExt;
+
+    let report = report(outcomes, failed, ips) + "\n";
+    let mut stream = tokio::net::UnixStream::connect(path)
+        .await
+        .wrap_err_with(|| format!("Failed to connect to report socket {path:?}"))?;
+    stream
+        .write_all(report.as_bytes())
+        .await
+        .wrap_err_with(|| format!("Failed to write to report socket {path:?}"))?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Runs `command` in a shell with the detected IPs and the report of the run
+/// in its environment. Returns its exit code
+pub fn exec<'a>(
+    command: &str,
+    outcomes: &[RecordOutcome],
+    failed: &[String],
+    ips: impl Iterator<Item = (IP, &'a str)>,
+) -> Result<ExitCode> {
+    let ips: Vec<_> = ips.collect();
+    let ip = |version| {
+        ips.iter()
+            .find(|(ip_version, _)| *ip_version == version)
+            .map(|(_, ip)| *ip)
+    };
+
+    #[cfg(unix)]
+    let mut shell = process::Command::new("sh");
+    #[cfg(unix)]
+    shell.arg("-c");
+    #[cfg(not(unix))]
+    let mut shell = process::Command::new("cmd");
+    #[cfg(not(unix))]
+    shell.arg("/C");
+
+    shell.arg(command).env(
+        "CF_DDNS_REPORT",
+        report(outcomes, failed, ips.iter().copied()),
+    );
+    for (name, version) in [("CF_DDNS_IPV4", IP::V4), ("CF_DDNS_IPV6", IP::V6)] {
+        if let Some(ip) = ip(version) {
+            shell.env(name, ip);
+        }
+    }
+
+    info!("Running {command:?}");
+    let status = shell
+        .status()
+        .wrap_err_with(|| format!("Failed to run {command:?}"))?;
+    // Killed by a signal if there is no code
+    Ok(status
+        .code()
+        .map_or(ExitCode::FAILURE, |code| (code as u8).into()))
+}
+
+#[cfg(not(unix))]
+pub async fn send<'a>(
+    _path: &Path,
+    _outcomes: &[RecordOutcome],
+    _failed: &[String],
+    _ips: impl Iterator<Item = (IP, &'a str)>,
+) -> Result<()> {
+    color_eyre::eyre::bail!("Unix sockets are not supported on this platform")
+}
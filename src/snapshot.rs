@@ -0,0 +1,127 @@
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+
+use cloudflare::endpoints::dns;
+use color_eyre::Result;
+use serde::{Deserialize, Serialize};
+
+/// Records of the configured names at some point in time, as written by
+/// `cf-ddns snapshot`
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Snapshot {
+    pub taken_on: String,
+    pub records: Vec<SnapshotRecord>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotRecord {
+    pub id: String,
+    pub name: String,
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub content: String,
+    pub ttl: u32,
+    pub proxied: bool,
+    pub modified_on: String,
+}
+
+impl SnapshotRecord {
+    pub fn of(record: &dns::DnsRecord) -> Result<Self> {
+        // DnsRecord isn't Serialize, but its content is
+        let content = serde_json::to_value(&record.content)?;
+        Ok(SnapshotRecord {
+            id: record.id.clone(),
+            name: record.name.clone(),
+            type_: content["type"].as_str().unwrap_or_default().to_string(),
+            content: content["content"].as_str().unwrap_or_default().to_string(),
+            ttl: record.ttl,
+            proxied: record.proxied,
+            modified_on: record.modified_on.to_rfc3339(),
+        })
+    }
+
+    /// Fields that differ from `new`, with their old and new values
+    fn changes(&self, new: &SnapshotRecord) -> Vec<(&'static str, String, String)> {
+        [
+            ("name", self.name.clone(), new.name.clone()),
+            ("type", self.type_.clone(), new.type_.clone()),
+            ("content", self.content.clone(), new.content.clone()),
+            ("ttl", self.ttl.to_string(), new.ttl.to_string()),
+            ("proxied", self.proxied.to_string(), new.proxied.to_string()),
+        ]
+        .into_iter()
+        .filter(|(_, old, new)| old != new)
+        .collect()
+    }
+}
+
+impl Display for SnapshotRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {} (id {}, ttl {}, proxied {})",
+            self.name, self.type_, self.content, self.id, self.ttl, self.proxied
+        )
+    }
+}
+
+/// Difference of a record between two snapshots
+#[derive(Debug)]
+pub enum RecordDiff<'a> {
+    Added(&'a SnapshotRecord),
+    Removed(&'a SnapshotRecord),
+    Modified {
+        record: &'a SnapshotRecord,
+        /// Changed fields with their old and new values
+        changes: Vec<(&'static str, String, String)>,
+    },
+}
+
+impl Display for RecordDiff<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecordDiff::Added(record) => write!(f, "+ {record}"),
+            RecordDiff::Removed(record) => write!(f, "- {record}"),
+            RecordDiff::Modified { record, changes } => {
+                write!(f, "~ {} {} (id {}):", record.name, record.type_, record.id)?;
+                for (field, old, new) in changes {
+                    write!(f, " {field} {old} -> {new}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Records added, removed or modified from `old` to `new`, matched by id
+pub fn diff<'a>(old: &'a Snapshot, new: &'a Snapshot) -> Vec<RecordDiff<'a>> {
+    let old_records: HashMap<&str, &SnapshotRecord> = old
+        .records
+        .iter()
+        .map(|record| (record.id.as_str(), record))
+        .collect();
+    let new_ids: Vec<&str> = new
+        .records
+        .iter()
+        .map(|record| record.id.as_str())
+        .collect();
+
+    let mut diffs: Vec<RecordDiff> = old
+        .records
+        .iter()
+        .filter(|record| !new_ids.contains(&record.id.as_str()))
+        .map(RecordDiff::Removed)
+        .collect();
+    for record in &new.records {
+        match old_records.get(record.id.as_str()) {
+            None => diffs.push(RecordDiff::Added(record)),
+            Some(old) => {
+                let changes = old.changes(record);
+                if !changes.is_empty() {
+                    diffs.push(RecordDiff::Modified { record, changes });
+                }
+            }
+        }
+    }
+    diffs
+}
@@ -0,0 +1,82 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+
+use color_eyre::eyre::{Context, ContextCompat};
+use color_eyre::Result;
+use log::debug;
+
+use crate::client::{Action, RecordOutcome};
+use crate::util::IP;
+
+const PREFIX: &str = "cf_ddns";
+
+/// Statsd uses `.` as a separator and `:`/`|` as delimiters
+fn sanitize(name: &str) -> String {
+    name.replace(['.', ':', '|', '@'], "_")
+}
+
+fn counter(name: &str, value: usize) -> String {
+    format!("{PREFIX}.{name}:{value}|c")
+}
+
+/// Pushes the results of a run to the statsd server at `addr`
+pub fn push<'a>(
+    addr: &str,
+    outcomes: &[RecordOutcome],
+    failed: &[String],
+    ips: impl Iterator<Item = (IP, &'a str)>,
+) -> Result<()> {
+    let addr: SocketAddr = addr
+        .to_socket_addrs()
+        .wrap_err_with(|| format!("Invalid statsd address {addr}"))?
+        .next()
+        .wrap_err_with(|| format!("{addr} did not resolve to any address"))?;
+
+    let mut lines = Vec::new();
+    for action in [
+        Action::Created,
+        Action::Updated,
+        Action::Unchanged,
+        Action::Deleted,
+    ] {
+        let count = outcomes.iter().filter(|o| o.action == action).count();
+        lines.push(counter(action.as_str(), count));
+    }
+    lines.push(counter("failed", failed.len()));
+
+    for outcome in outcomes {
+        let name = format!(
+            "record.{}.{}.{}",
+            sanitize(&outcome.fqdn),
+            outcome.type_,
+            outcome.action.as_str()
+        );
+        lines.push(counter(&name, 1));
+    }
+    for subdomain in failed {
+        lines.push(counter(
+            &format!("subdomain.{}.failed", sanitize(subdomain)),
+            1,
+        ));
+    }
+    for (version, ip) in ips {
+        let version = match version {
+            IP::V4 => "ipv4",
+            IP::V6 => "ipv6",
+        };
+        lines.push(format!("{PREFIX}.ip.{version}.{}:1|g", sanitize(ip)));
+    }
+
+    let bind_addr = match addr {
+        SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+    let socket = UdpSocket::bind((bind_addr, 0))?;
+    for line in lines {
+        debug!("statsd: {line}");
+        socket
+            .send_to(line.as_bytes(), addr)
+            .wrap_err_with(|| format!("Failed to send metrics to {addr}"))?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,64 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use clap::ValueEnum;
+use color_eyre::eyre::Context;
+use color_eyre::Result;
+
+use crate::util::write_atomic;
+
+/// How a trigger file signals that the records should be updated
+#[derive(ValueEnum, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerMode {
+    /// The file exists. It is removed after a successful update
+    #[default]
+    Remove,
+    /// The file was modified after the last successful update, which is
+    /// recorded in `<file>.last-run`
+    Touch,
+}
+
+fn stamp_path(path: &Path) -> PathBuf {
+    let mut stamp = path.as_os_str().to_owned();
+    stamp.push(".last-run");
+    stamp.into()
+}
+
+/// Modification time of `path`, `None` if it doesn't exist
+fn modified(path: &Path) -> Result<Option<std::time::SystemTime>> {
+    match fs::metadata(path) {
+        Ok(metadata) => Ok(Some(metadata.modified()?)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).wrap_err_with(|| format!("Failed to read metadata of {path:?}")),
+    }
+}
+
+/// Whether the trigger file at `path` signals that the records should be updated
+pub fn is_triggered(path: &Path, mode: TriggerMode) -> Result<bool> {
+    let Some(modified_at) = modified(path)? else {
+        return Ok(false);
+    };
+    Ok(match mode {
+        TriggerMode::Remove => true,
+        TriggerMode::Touch => match modified(&stamp_path(path))? {
+            Some(last_run) => modified_at > last_run,
+            None => true,
+        },
+    })
+}
+
+/// Marks the trigger at `path` as handled after a successful update
+pub fn acknowledge(path: &Path, mode: TriggerMode) -> Result<()> {
+    match mode {
+        TriggerMode::Remove => match fs::remove_file(path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                Err(e).wrap_err_with(|| format!("Failed to remove trigger file {path:?}"))
+            }
+            _ => Ok(()),
+        },
+        TriggerMode::Touch => {
+            write_atomic(&stamp_path(path), &(chrono::Utc::now().to_rfc3339() + "\n"))
+        }
+    }
+}
@@ -1,7 +1,20 @@
-use color_eyre::eyre::{ensure, Context, ContextCompat};
+use chrono::NaiveTime;
+use clap::ValueEnum;
+use color_eyre::eyre::{bail, ensure, eyre, Context, ContextCompat};
 use color_eyre::Result;
+use futures::future::{FutureExt, LocalBoxFuture};
+use futures::stream::{self, StreamExt};
+use log::{debug, warn};
 use reqwest::Response;
-use std::fmt::Display;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::Path;
+use std::time::Duration;
+
+use crate::config::{Detection, HealthCheck, Provider};
 
 // Ensure Success is copied from here: https://github.com/thomasqueirozb/autovor/blob/master/src/helper.rs
 pub trait EnsureSuccess {
@@ -57,26 +70,590 @@ impl EnsureSuccess for Response {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 #[repr(u8)]
 pub enum IP {
     V4,
     V6,
 }
 
-pub async fn get_ip(version: IP) -> Result<String> {
+impl Display for IP {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IP::V4 => write!(f, "IPv4"),
+            IP::V6 => write!(f, "IPv6"),
+        }
+    }
+}
+
+/// Where the IP address used as record content comes from
+#[derive(Deserialize, ValueEnum, Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum IpSource {
+    /// Public IP as seen by Cloudflare's trace endpoint
+    #[default]
+    Cloudflare,
+    /// Private (RFC1918/ULA) address of one of the machine's interfaces
+    Local,
+    /// Public IP agreed upon by the providers configured in `[detection]`
+    Providers,
+    /// Public IP reported by the instance metadata service of the cloud the
+    /// machine runs on
+    Metadata,
+    /// External IPv4 of the local gateway, asked over UPnP IGD
+    Upnp,
+}
+
+/// Cloud whose instance metadata service is queried by the metadata source
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum MetadataProvider {
+    Aws,
+    Gcp,
+    Azure,
+}
+
+/// What detected IPs are expected to be. The local source isn't checked
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Expect {
+    #[default]
+    Any,
+    /// Globally routable: not private, loopback, link-local or CGNAT
+    Public,
+}
+
+impl Expect {
+    pub fn check(self, ip: IpAddr) -> Result<()> {
+        if self == Expect::Any {
+            return Ok(());
+        }
+        let public = !is_private(&ip)
+            && !ip.is_loopback()
+            && !ip.is_unspecified()
+            && match ip {
+                IpAddr::V4(ip) => !ip.is_link_local() && !ip.is_broadcast() && !is_cgnat(ip),
+                // Link-local unicast (fe80::/10)
+                IpAddr::V6(ip) => (ip.segments()[0] & 0xffc0) != 0xfe80,
+            };
+        ensure!(public, "{ip} is not a public address");
+        Ok(())
+    }
+}
+
+impl MetadataProvider {
+    const ALL: [MetadataProvider; 3] = [
+        MetadataProvider::Aws,
+        MetadataProvider::Gcp,
+        MetadataProvider::Azure,
+    ];
+}
+
+/// Source of the IPs published in A/AAAA records. cf-ddns is only built as a
+/// binary, so the providers are the built-in ones and `FixedIps`
+pub trait IpProvider {
+    fn detect(&self, version: IP) -> LocalBoxFuture<'_, Result<IpAddr>>;
+}
+
+/// Public IP as seen by Cloudflare's trace endpoint
+pub struct CloudflareTrace<'a> {
+    pub source_address: Option<IpAddr>,
+    pub timeout: Duration,
+    pub user_agent: &'a str,
+}
+
+impl IpProvider for CloudflareTrace<'_> {
+    fn detect(&self, version: IP) -> LocalBoxFuture<'_, Result<IpAddr>> {
+        async move {
+            let ip = get_cloudflare_ip(version, self.source_address, self.timeout, self.user_agent)
+                .await?;
+            ip.parse()
+                .wrap_err_with(|| format!("Cloudflare returned an invalid IP address: {ip:?}"))
+        }
+        .boxed_local()
+    }
+}
+
+/// Private (RFC1918/ULA) address of one of the machine's interfaces
+pub struct LocalInterface;
+
+impl IpProvider for LocalInterface {
+    fn detect(&self, version: IP) -> LocalBoxFuture<'_, Result<IpAddr>> {
+        async move { get_local_ip(version) }.boxed_local()
+    }
+}
+
+/// Public IP agreed upon by at least `min_agreement` of `providers`
+pub struct Consensus<'a> {
+    pub providers: &'a [Provider],
+    pub min_agreement: usize,
+    /// Maximum number of providers queried at the same time
+    pub concurrency: usize,
+    /// Accept invalid TLS certificates
+    pub insecure: bool,
+    pub source_address: Option<IpAddr>,
+    pub timeout: Duration,
+    pub user_agent: &'a str,
+    /// Reports that don't meet it are discarded
+    pub expect: Expect,
+}
+
+impl IpProvider for Consensus<'_> {
+    fn detect(&self, version: IP) -> LocalBoxFuture<'_, Result<IpAddr>> {
+        async move {
+            let ip = detect_ip_consensus(self, version).await?;
+            Ok(ip.parse()?)
+        }
+        .boxed_local()
+    }
+}
+
+/// Public IP reported by the instance metadata service of `provider`, or of
+/// the first cloud whose service answers if it isn't set
+pub struct CloudMetadata<'a> {
+    pub provider: Option<MetadataProvider>,
+    pub timeout: Duration,
+    pub user_agent: &'a str,
+}
+
+impl IpProvider for CloudMetadata<'_> {
+    fn detect(&self, version: IP) -> LocalBoxFuture<'_, Result<IpAddr>> {
+        // The service is on the local link, so it either answers right away
+        // or the machine isn't on that cloud
+        const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+        async move {
+            let client = reqwest::Client::builder()
+                .no_proxy()
+                .connect_timeout(CONNECT_TIMEOUT)
+                .timeout(self.timeout)
+                .user_agent(self.user_agent)
+                .build()?;
+            if let Some(provider) = self.provider {
+                return get_metadata_ip(&client, provider, version).await;
+            }
+
+            let mut errors = Vec::new();
+            for provider in MetadataProvider::ALL {
+                match get_metadata_ip(&client, provider, version).await {
+                    Ok(ip) => {
+                        debug!("Got the {version} from the {provider:?} instance metadata");
+                        return Ok(ip);
+                    }
+                    Err(e) => errors.push(format!("{provider:?}: {e:#}")),
+                }
+            }
+            bail!(
+                "No instance metadata service reported an {version} address:\n{}",
+                errors.join("\n")
+            )
+        }
+        .boxed_local()
+    }
+}
+
+/// External IP of the UPnP IGD gateway of the local network. Gateways only
+/// report their IPv4
+pub struct UpnpGateway {
+    pub source_address: Option<IpAddr>,
+    pub timeout: Duration,
+}
+
+impl IpProvider for UpnpGateway {
+    fn detect(&self, version: IP) -> LocalBoxFuture<'_, Result<IpAddr>> {
+        async move {
+            ensure!(
+                version == IP::V4,
+                "UPnP gateways don't report an IPv6 address, use another source for AAAA records"
+            );
+            let mut options = igd_next::SearchOptions {
+                timeout: Some(self.timeout),
+                ..Default::default()
+            };
+            if let Some(source_address) = self.source_address {
+                options.bind_addr = SocketAddr::new(source_address, 0);
+            }
+
+            let detect = async {
+                let gateway = igd_next::aio::tokio::search_gateway(options)
+                    .await
+                    .wrap_err("Failed to find a UPnP gateway")?;
+                debug!("Found UPnP gateway {gateway}");
+                gateway
+                    .get_external_ip()
+                    .await
+                    .wrap_err_with(|| format!("Failed to get the external IP of {gateway}"))
+            };
+            let ip = tokio::time::timeout(self.timeout, detect)
+                .await
+                .wrap_err("Timed out asking the UPnP gateway for its external IP")??;
+            ensure!(
+                ip.is_ipv4(),
+                "UPnP gateway reported {ip}, which is not an IPv4"
+            );
+            Ok(ip)
+        }
+        .boxed_local()
+    }
+}
+
+/// Always the IP of the requested version in the list
+pub struct FixedIps(pub Vec<IpAddr>);
+
+impl IpProvider for FixedIps {
+    fn detect(&self, version: IP) -> LocalBoxFuture<'_, Result<IpAddr>> {
+        let ip = self.0.iter().copied().find(|ip| match version {
+            IP::V4 => ip.is_ipv4(),
+            IP::V6 => ip.is_ipv6(),
+        });
+        async move { ip.with_context(|| format!("No {version} address configured")) }.boxed_local()
+    }
+}
+
+/// Built-in provider for `source`. `source_address` binds the detection
+/// requests to a local address, so the IP reported back is the one of that
+/// uplink, or finds the UPnP gateway through it. Ignored for the local and
+/// metadata sources
+pub fn builtin_provider<'a>(
+    source: IpSource,
+    detection: &'a Detection,
+    source_address: Option<IpAddr>,
+    timeout: Duration,
+) -> Box<dyn IpProvider + 'a> {
+    match source {
+        IpSource::Cloudflare => Box::new(CloudflareTrace {
+            source_address,
+            timeout,
+            user_agent: &detection.user_agent,
+        }),
+        IpSource::Local => Box::new(LocalInterface),
+        IpSource::Providers => Box::new(Consensus {
+            providers: &detection.providers,
+            min_agreement: detection.min_agreement,
+            concurrency: detection.concurrency,
+            insecure: detection.insecure,
+            source_address,
+            timeout,
+            user_agent: &detection.user_agent,
+            expect: detection.expect,
+        }),
+        IpSource::Metadata => Box::new(CloudMetadata {
+            provider: detection.metadata_provider,
+            timeout,
+            user_agent: &detection.user_agent,
+        }),
+        IpSource::Upnp => Box::new(UpnpGateway {
+            source_address,
+            timeout,
+        }),
+    }
+}
+
+fn is_private(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_private(),
+        // Unique local addresses (fc00::/7)
+        IpAddr::V6(ip) => (ip.segments()[0] & 0xfe00) == 0xfc00,
+    }
+}
+
+fn get_local_ip(version: IP) -> Result<IpAddr> {
+    let ip = if_addrs::get_if_addrs()
+        .wrap_err("Failed to list network interfaces")?
+        .into_iter()
+        .map(|interface| interface.ip())
+        .filter(|ip| {
+            matches!(
+                (version, ip),
+                (IP::V4, IpAddr::V4(_)) | (IP::V6, IpAddr::V6(_))
+            )
+        })
+        .find(is_private)
+        .with_context(|| format!("No interface has a private {version} address"))?;
+
+    Ok(ip)
+}
+
+/// Client whose connections are bound to the address family of `version`, so
+/// the IP reported back by a provider is of that family. Connections are bound
+/// to `source_address` instead if it is of the same family
+fn family_client(
+    version: IP,
+    source_address: Option<IpAddr>,
+    timeout: Duration,
+    user_agent: &str,
+) -> Result<reqwest::Client> {
+    Ok(family_client_builder(version, source_address, timeout, user_agent).build()?)
+}
+
+fn family_client_builder(
+    version: IP,
+    source_address: Option<IpAddr>,
+    timeout: Duration,
+    user_agent: &str,
+) -> reqwest::ClientBuilder {
+    let local_address = match (version, source_address) {
+        (IP::V4, Some(addr @ IpAddr::V4(_))) | (IP::V6, Some(addr @ IpAddr::V6(_))) => addr,
+        (IP::V4, _) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        (IP::V6, _) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+
+    reqwest::Client::builder()
+        .local_address(local_address)
+        .timeout(timeout)
+        .user_agent(user_agent)
+}
+
+/// Accepts both plain text responses and Cloudflare trace style `ip=` lines
+fn parse_provider_response(version: IP, url: &str, text: &str) -> Result<String> {
+    let ip = text
+        .lines()
+        .find_map(|line| line.strip_prefix("ip="))
+        .unwrap_or(text)
+        .trim();
+
+    let parsed: IpAddr = ip
+        .parse()
+        .with_context(|| format!("{url} returned an invalid IP address: {ip:?}"))?;
+    ensure!(
+        matches!(
+            (version, parsed),
+            (IP::V4, IpAddr::V4(_)) | (IP::V6, IpAddr::V6(_))
+        ),
+        "{url} returned {parsed}, which is not an {version} address"
+    );
+
+    Ok(parsed.to_string())
+}
+
+/// Public IP of the instance from the metadata service of `provider`
+async fn get_metadata_ip(
+    client: &reqwest::Client,
+    provider: MetadataProvider,
+    version: IP,
+) -> Result<IpAddr> {
+    const METADATA_ADDR: &str = "169.254.169.254";
+
+    let request = match provider {
+        // IMDSv2, which requires a session token
+        MetadataProvider::Aws => {
+            let token = client
+                .put(format!("http://{METADATA_ADDR}/latest/api/token"))
+                .header("X-aws-ec2-metadata-token-ttl-seconds", "60")
+                .send()
+                .await
+                .wrap_err("Failed to get an instance metadata token")?
+                .ensure_success()?
+                .text()
+                .await?;
+            let path = match version {
+                IP::V4 => "public-ipv4",
+                IP::V6 => "ipv6",
+            };
+            client
+                .get(format!("http://{METADATA_ADDR}/latest/meta-data/{path}"))
+                .header("X-aws-ec2-metadata-token", token)
+        }
+        MetadataProvider::Gcp => {
+            let path = match version {
+                IP::V4 => "access-configs/0/external-ip",
+                IP::V6 => "ipv6-access-configs/0/external-ipv6",
+            };
+            client
+                .get(format!(
+                    "http://{METADATA_ADDR}/computeMetadata/v1/instance/network-interfaces/0/{path}"
+                ))
+                .header("Metadata-Flavor", "Google")
+        }
+        MetadataProvider::Azure => {
+            ensure!(
+                version == IP::V4,
+                "Azure's instance metadata doesn't report public IPv6 addresses"
+            );
+            client
+                .get(format!(
+                    "http://{METADATA_ADDR}/metadata/instance/network/interface/0/ipv4/\
+                    ipAddress/0/publicIpAddress?api-version=2021-02-01&format=text"
+                ))
+                .header("Metadata", "true")
+        }
+    };
+
+    let response = request
+        .send()
+        .await
+        .wrap_err("Failed to query the instance metadata")?
+        .ensure_success()?;
+    let url = response.url().to_string();
+    let text = response.text().await?;
+    Ok(parse_provider_response(version, &url, &text)?.parse()?)
+}
+
+async fn get_provider_ip(
+    client: &reqwest::Client,
+    version: IP,
+    provider: &Provider,
+) -> Result<String> {
+    let url = &provider.url;
+    let mut request = client.get(url);
+    for (name, value) in &provider.headers {
+        request = request.header(name, value);
+    }
+
+    let text = request
+        .send()
+        .await
+        .with_context(|| format!("Failed to query {url}"))?
+        .ensure_success()?
+        .text()
+        .await?;
+
+    parse_provider_response(version, url, &text)
+}
+
+/// Queries all providers of `consensus` concurrently and returns the IP
+/// reported by at least `min_agreement` of them, failing if several are.
+/// Reported IPs that don't meet `expect` count as failed providers
+pub async fn detect_ip_consensus(consensus: &Consensus<'_>, version: IP) -> Result<String> {
+    let Consensus {
+        providers,
+        min_agreement,
+        concurrency,
+        insecure,
+        source_address,
+        timeout,
+        user_agent,
+        expect,
+    } = *consensus;
+    let client = family_client_builder(version, source_address, timeout, user_agent)
+        .danger_accept_invalid_certs(insecure)
+        .build()?;
+    let results: Vec<_> = stream::iter(providers)
+        .map(|provider| {
+            get_provider_ip(&client, version, provider).map(move |result| (provider, result))
+        })
+        .buffer_unordered(concurrency)
+        .collect()
+        .await;
+
+    let mut votes: HashMap<String, Vec<&str>> = HashMap::new();
+    for (provider, result) in results {
+        let url = provider.url.as_str();
+        let checked = result.and_then(|ip| {
+            expect
+                .check(ip.parse()?)
+                .wrap_err_with(|| format!("{url} reported a bogus {version}"))?;
+            Ok(ip)
+        });
+        match checked {
+            Ok(ip) => {
+                debug!("{url} reported {version} {ip}");
+                votes.entry(ip).or_default().push(url);
+            }
+            Err(e) => warn!("{e:?}"),
+        }
+    }
+
+    agreed_ip(&votes, min_agreement, providers.len(), version)
+}
+
+/// IP of `votes`, the URLs of the providers that reported each IP, that at
+/// least `min_agreement` of the `total` providers agree on. Fails if none or
+/// several of them do, as a lower min_agreement lets two IPs tie
+fn agreed_ip(
+    votes: &HashMap<String, Vec<&str>>,
+    min_agreement: usize,
+    total: usize,
+    version: IP,
+) -> Result<String> {
+    let mut agreed: Vec<&String> = votes
+        .iter()
+        .filter(|(_, urls)| urls.len() >= min_agreement)
+        .map(|(ip, _)| ip)
+        .collect();
+    if let [ip] = agreed[..] {
+        return Ok(ip.clone());
+    }
+
+    for (ip, urls) in votes {
+        warn!("{ip} reported by {urls:?}");
+    }
+    agreed.sort();
+    ensure!(
+        agreed.is_empty(),
+        "Providers disagree on the {version} address: {agreed:?} were each reported by at \
+        least {min_agreement} of {total} providers"
+    );
+    bail!("Fewer than {min_agreement} of {total} providers agreed on the {version} address");
+}
+
+const CF_TRACE_HOSTNAME: &str = "one.one.one.one";
+
+/// Requests the trace endpoint by hostname first, so any of the addresses it
+/// resolves to can answer, and falls back to Cloudflare's literal IP
+async fn get_cloudflare_ip(
+    version: IP,
+    source_address: Option<IpAddr>,
+    timeout: Duration,
+    user_agent: &str,
+) -> Result<String> {
     const CF_IPV4_URL: &str = "https://1.1.1.1/cdn-cgi/trace";
     const CF_IPV6_URL: &str = "https://[2606:4700:4700::1111]/cdn-cgi/trace";
-    let (ip_str, url) = match version {
-        IP::V4 => ("IPv4", CF_IPV4_URL),
-        IP::V6 => ("IPv6", CF_IPV6_URL),
+    let url = match version {
+        IP::V4 => CF_IPV4_URL,
+        IP::V6 => CF_IPV6_URL,
     };
 
-    let response = match reqwest::get(url).await {
+    match get_cloudflare_ip_by_hostname(version, source_address, timeout, user_agent).await {
+        Ok(ip) => Ok(ip),
+        Err(e) => {
+            warn!(
+                "Failed to get the {version} from {CF_TRACE_HOSTNAME}, retrying with {url}: {e:#}"
+            );
+            let client = family_client(version, source_address, timeout, user_agent)?;
+            get_trace_ip(&client, version, url).await
+        }
+    }
+}
+
+/// Requests the trace endpoint by hostname, resolving it only to addresses
+/// of the family of `version` so the connection can't use the other family.
+/// The connection is attempted to each of them until one succeeds
+async fn get_cloudflare_ip_by_hostname(
+    version: IP,
+    source_address: Option<IpAddr>,
+    timeout: Duration,
+    user_agent: &str,
+) -> Result<String> {
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((CF_TRACE_HOSTNAME, 443))
+        .await
+        .wrap_err_with(|| format!("Failed to resolve {CF_TRACE_HOSTNAME}"))?
+        .filter(|addr| {
+            matches!(
+                (version, addr),
+                (IP::V4, SocketAddr::V4(_)) | (IP::V6, SocketAddr::V6(_))
+            )
+        })
+        .collect();
+    ensure!(
+        !addrs.is_empty(),
+        "{CF_TRACE_HOSTNAME} didn't resolve to any {version} address"
+    );
+
+    let client = family_client_builder(version, source_address, timeout, user_agent)
+        .resolve_to_addrs(CF_TRACE_HOSTNAME, &addrs)
+        .build()?;
+    let url = format!("https://{CF_TRACE_HOSTNAME}/cdn-cgi/trace");
+    get_trace_ip(&client, version, &url).await
+}
+
+/// IP reported by the Cloudflare trace endpoint at `url`
+async fn get_trace_ip(client: &reqwest::Client, version: IP, url: &str) -> Result<String> {
+    let response = match client.get(url).send().await {
         Ok(r) => r,
         Err(e) => {
             return if e.is_connect() {
-                Err(e).with_context(|| format!("Connection error, check {ip_str} connectivity"))
+                Err(e).with_context(|| format!("Connection error, check {version} connectivity"))
             } else {
                 Err(e.into())
             }
@@ -89,6 +666,197 @@ pub async fn get_ip(version: IP) -> Result<String> {
         .with_context(|| {
             format!("Couldn't find ip= in the response from {url}\nFull response: {text}")
         })?;
+    let is_version = match (version, ip.parse::<IpAddr>()) {
+        (IP::V4, Ok(addr)) => addr.is_ipv4(),
+        (IP::V6, Ok(addr)) => addr.is_ipv6(),
+        // Reported as invalid by the caller
+        (_, Err(_)) => true,
+    };
+    ensure!(
+        is_version,
+        "{url} reported {ip}, which isn't an {version} address"
+    );
 
     Ok(ip)
 }
+
+/// IP of `version` in a file written by --write-ip-file, unless the file is
+/// older than `max_age`
+pub fn read_ip_file(path: &Path, version: IP, max_age: Duration) -> Result<String> {
+    let age = fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .wrap_err_with(|| format!("Failed to read {path:?}"))?
+        .elapsed()
+        .unwrap_or_default();
+    ensure!(
+        age <= max_age,
+        "{path:?} was written {}s ago",
+        age.as_secs()
+    );
+
+    fs::read_to_string(path)
+        .wrap_err_with(|| format!("Failed to read {path:?}"))?
+        .lines()
+        .map(str::trim)
+        .find(|line| {
+            matches!(
+                (version, line.parse()),
+                (IP::V4, Ok(IpAddr::V4(_))) | (IP::V6, Ok(IpAddr::V6(_)))
+            )
+        })
+        .map(String::from)
+        .with_context(|| format!("{path:?} has no {version} address"))
+}
+
+/// Zone name as it is compared and joined to the subdomains: lowercase and
+/// without the trailing dot of a fully qualified name
+pub fn normalize_zone_name(name: &str) -> String {
+    name.strip_suffix('.').unwrap_or(name).to_ascii_lowercase()
+}
+
+/// Whether `ip` is in the carrier-grade NAT shared address space (100.64.0.0/10)
+pub fn is_cgnat(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    a == 100 && (b & 0xc0) == 64
+}
+
+/// Network address of `ip`, keeping only its first `bits` bits
+pub fn ipv6_network(ip: Ipv6Addr, bits: u8) -> Ipv6Addr {
+    let mask = u128::MAX.checked_shl(128 - u32::from(bits)).unwrap_or(0);
+    Ipv6Addr::from(u128::from(ip) & mask)
+}
+
+/// Fails if `ip` doesn't pass `check` within `timeout`. HTTP checks are sent
+/// to `ip` rather than to what the host of the URL resolves to. A host name is
+/// still used for TLS and the Host header
+pub async fn health_check(ip: IpAddr, check: &HealthCheck, timeout: Duration) -> Result<()> {
+    match check {
+        HealthCheck::Tcp(port) => {
+            tokio::time::timeout(timeout, tokio::net::TcpStream::connect((ip, *port)))
+                .await
+                .wrap_err_with(|| format!("Timed out connecting to {ip} port {port}"))?
+                .wrap_err_with(|| format!("Failed to connect to {ip} port {port}"))?;
+        }
+        HealthCheck::Http(url) => {
+            let mut parsed =
+                reqwest::Url::parse(url).wrap_err_with(|| format!("Invalid URL {url:?}"))?;
+            let port = parsed
+                .port_or_known_default()
+                .with_context(|| format!("{url} has no port"))?;
+            let mut client = reqwest::Client::builder().timeout(timeout);
+            match parsed.domain() {
+                Some(host) => client = client.resolve(host, SocketAddr::new(ip, port)),
+                None => parsed
+                    .set_ip_host(ip)
+                    .map_err(|()| eyre!("Can't send {url} to {ip}"))?,
+            }
+            client
+                .build()?
+                .get(parsed)
+                .send()
+                .await
+                .wrap_err_with(|| format!("Failed to query {url}"))?
+                .ensure_success()?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes to a temporary file next to `path` and renames it over `path`, so
+/// readers never see a partially written file
+pub fn write_atomic(path: &Path, contents: &str) -> Result<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+
+    fs::write(&tmp_path, contents).wrap_err_with(|| format!("Failed to write {tmp_path:?}"))?;
+    fs::rename(&tmp_path, path)
+        .wrap_err_with(|| format!("Failed to rename {tmp_path:?} to {path:?}"))?;
+    Ok(())
+}
+
+/// Whether `time` falls within `window`, formatted as `HH:MM-HH:MM`. Windows
+/// ending before they start wrap around midnight
+pub fn in_time_window(window: &str, time: NaiveTime) -> Result<bool> {
+    let parse = |s: &str| {
+        NaiveTime::parse_from_str(s.trim(), "%H:%M")
+            .wrap_err_with(|| format!("Invalid time {s:?} in window {window:?}, expected HH:MM"))
+    };
+
+    let (start, end) = window
+        .split_once('-')
+        .with_context(|| format!("Invalid time window {window:?}, expected HH:MM-HH:MM"))?;
+    let (start, end) = (parse(start)?, parse(end)?);
+
+    Ok(if start <= end {
+        start <= time && time < end
+    } else {
+        time >= start || time < end
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cgnat_boundaries() {
+        let ip = |ip: &str| ip.parse::<Ipv4Addr>().unwrap();
+        assert!(!is_cgnat(ip("100.63.255.255")));
+        assert!(is_cgnat(ip("100.64.0.0")));
+        assert!(is_cgnat(ip("100.100.1.1")));
+        assert!(is_cgnat(ip("100.127.255.255")));
+        assert!(!is_cgnat(ip("100.128.0.0")));
+        assert!(!is_cgnat(ip("101.64.0.0")));
+    }
+
+    #[test]
+    fn zone_names() {
+        assert_eq!(normalize_zone_name("example.com"), "example.com");
+        assert_eq!(normalize_zone_name("example.com."), "example.com");
+        assert_eq!(normalize_zone_name("Example.COM."), "example.com");
+        // Only the dot of a fully qualified name is stripped
+        assert_eq!(normalize_zone_name("example.com.."), "example.com.");
+    }
+
+    #[tokio::test]
+    async fn http_health_check_reaches_the_origin() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let _ = stream.read(&mut [0; 1024]).await;
+                let response = "HTTP/1.1 200 OK\r\ncontent-length: 0\r\nconnection: close\r\n\r\n";
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+
+        // Neither host leads to the origin on its own
+        let origin = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        for host in ["origin.invalid", "192.0.2.1"] {
+            let check = HealthCheck::Http(format!("http://{host}:{port}/health"));
+            health_check(origin, &check, Duration::from_secs(5))
+                .await
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn tied_consensus() {
+        let votes = |reports: &[(&str, usize)]| -> HashMap<String, Vec<&str>> {
+            reports
+                .iter()
+                .map(|(ip, count)| (ip.to_string(), vec!["https://provider"; *count]))
+                .collect()
+        };
+        let tie = votes(&[("192.0.2.1", 2), ("192.0.2.2", 2)]);
+        assert!(agreed_ip(&tie, 2, 4, IP::V4).is_err());
+        assert!(agreed_ip(&tie, 3, 4, IP::V4).is_err());
+
+        let majority = votes(&[("192.0.2.1", 3), ("192.0.2.2", 2)]);
+        assert_eq!(agreed_ip(&majority, 3, 5, IP::V4).unwrap(), "192.0.2.1");
+        assert!(agreed_ip(&majority, 2, 5, IP::V4).is_err());
+    }
+}
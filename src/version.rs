@@ -0,0 +1,23 @@
+use chrono::DateTime;
+use serde_json::json;
+
+const GIT_COMMIT: &str = env!("CF_DDNS_GIT_COMMIT");
+const BUILD_TIMESTAMP: &str = env!("CF_DDNS_BUILD_TIMESTAMP");
+const CLOUDFLARE_VERSION: &str = env!("CF_DDNS_CLOUDFLARE_VERSION");
+
+/// Build metadata captured by build.rs, as JSON
+pub fn info() -> String {
+    let build_date = BUILD_TIMESTAMP
+        .parse()
+        .ok()
+        .and_then(|timestamp| DateTime::from_timestamp(timestamp, 0))
+        .map(|date| date.to_rfc3339());
+
+    json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "git_commit": GIT_COMMIT,
+        "build_date": build_date,
+        "cloudflare_version": CLOUDFLARE_VERSION,
+    })
+    .to_string()
+}
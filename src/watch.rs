@@ -0,0 +1,115 @@
+use color_eyre::Result;
+
+/// Notifications sent by the kernel when an address is added to or removed
+/// from a network interface
+#[cfg(target_os = "linux")]
+pub struct AddressWatcher {
+    socket: tokio::io::unix::AsyncFd<std::os::fd::OwnedFd>,
+}
+
+#[cfg(target_os = "linux")]
+impl AddressWatcher {
+    /// Subscribes to the IPv4 and IPv6 address groups of a netlink socket
+    pub fn new() -> Result<Self> {
+        use color_eyre::eyre::WrapErr;
+        use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+        use std::{io, mem};
+
+        // SAFETY: the fd is owned as soon as it is created and only used
+        // through the OwnedFd afterwards
+        let socket = unsafe {
+            let fd = libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                libc::NETLINK_ROUTE,
+            );
+            if fd < 0 {
+                return Err(io::Error::last_os_error()).wrap_err("Failed to open netlink socket");
+            }
+            OwnedFd::from_raw_fd(fd)
+        };
+
+        // SAFETY: sockaddr_nl is plain data, for which all zeroes is valid
+        let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        addr.nl_groups = (libc::RTMGRP_IPV4_IFADDR | libc::RTMGRP_IPV6_IFADDR) as u32;
+        // SAFETY: addr is a valid sockaddr_nl of the given size
+        let ret = unsafe {
+            libc::bind(
+                socket.as_raw_fd(),
+                (&addr as *const libc::sockaddr_nl).cast(),
+                mem::size_of_val(&addr) as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error())
+                .wrap_err("Failed to subscribe to address changes");
+        }
+
+        Ok(AddressWatcher {
+            socket: tokio::io::unix::AsyncFd::new(socket)?,
+        })
+    }
+
+    /// Resolves once an address changed. Changes usually come in bursts (e.g.
+    /// DHCP or SLAAC), so the ones arriving shortly after are coalesced
+    pub async fn changed(&mut self) -> Result<()> {
+        const SETTLE_TIME: std::time::Duration = std::time::Duration::from_secs(2);
+
+        self.recv().await?;
+        tokio::time::sleep(SETTLE_TIME).await;
+        while self.try_recv()? {}
+        Ok(())
+    }
+
+    /// Discards the next notification, waiting for one if there is none
+    async fn recv(&self) -> Result<()> {
+        loop {
+            let mut guard = self.socket.readable().await?;
+            if let Ok(result) = guard.try_io(|socket| recv(socket.get_ref())) {
+                return Ok(result?);
+            }
+        }
+    }
+
+    /// Discards the next notification. Returns false if there is none
+    fn try_recv(&self) -> Result<bool> {
+        match recv(self.socket.get_ref()) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// Reads a message from the netlink socket. Its contents don't matter since
+/// only address notifications are subscribed to
+#[cfg(target_os = "linux")]
+fn recv(socket: &std::os::fd::OwnedFd) -> std::io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let mut buf = [0u8; 8192];
+    // SAFETY: buf is valid for writes of its length
+    let len = unsafe { libc::recv(socket.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len(), 0) };
+    match std::io::Error::last_os_error() {
+        // Notifications were dropped because too many arrived, which still
+        // means something changed
+        e if len < 0 && e.raw_os_error() == Some(libc::ENOBUFS) => Ok(()),
+        e if len < 0 => Err(e),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub struct AddressWatcher;
+
+#[cfg(not(target_os = "linux"))]
+impl AddressWatcher {
+    pub fn new() -> Result<Self> {
+        color_eyre::eyre::bail!("Watching network interfaces is only supported on Linux")
+    }
+
+    pub async fn changed(&mut self) -> Result<()> {
+        std::future::pending().await
+    }
+}
@@ -0,0 +1,86 @@
+//! The one-shot job contract (`--once --max-runtime 30 --output json`) for the
+//! parts that don't need the Cloudflare API: the JSON report is the only thing
+//! written to stdout, and the exit code tells the failures apart
+
+use std::fs;
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+fn config(name: &str, contents: &str) -> PathBuf {
+    let path = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(name);
+    fs::write(&path, contents).unwrap();
+    path
+}
+
+fn cf_ddns(config: &PathBuf, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_cf-ddns"))
+        .arg("--config")
+        .arg(config)
+        .args(args)
+        .env("CF_API_TOKEN", "dummy")
+        .env("RUST_LOG", "debug")
+        .output()
+        .unwrap()
+}
+
+fn one_shot(config: &PathBuf, extra_args: &[&str]) -> Output {
+    let args = ["--once", "--max-runtime", "30", "--output", "json"];
+    cf_ddns(config, &[&args[..], extra_args].concat())
+}
+
+#[test]
+fn report_on_stdout() {
+    let output = one_shot(&config("empty.toml", ""), &[]);
+    assert_eq!(output.status.code(), Some(0), "{output:?}");
+
+    // Logs go to stderr, so stdout is the report alone
+    let report: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(report["records"], serde_json::json!([]));
+    assert_eq!(report["failed"], serde_json::json!([]));
+    assert!(report["timestamp"].is_string());
+}
+
+#[test]
+fn invalid_config() {
+    let output = one_shot(
+        &config("invalid.toml", "[subdomains]\nttl = \"soon\"\n"),
+        &[],
+    );
+    assert_eq!(output.status.code(), Some(3), "{output:?}");
+    assert!(output.stdout.is_empty());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Invalid configuration"), "{stderr}");
+}
+
+#[test]
+fn once_conflicts_with_interval() {
+    let output = one_shot(&config("interval.toml", ""), &["--interval", "60"]);
+    assert_eq!(output.status.code(), Some(2), "{output:?}");
+    assert!(output.stdout.is_empty());
+}
+
+#[test]
+fn soft_fail_errors() {
+    let invalid = config("soft-invalid.toml", "[subdomains]\nttl = \"soon\"\n");
+    let output = one_shot(&invalid, &["--soft-fail"]);
+    assert_eq!(output.status.code(), Some(0), "{output:?}");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Invalid configuration"), "{stderr}");
+}
+
+#[test]
+fn diff_only_errors() {
+    // Neither the configuration error (3) nor the invalid arguments (2) keep
+    // their exit codes, as 2 means drift
+    let invalid = config("diff-invalid.toml", "[subdomains]\nttl = \"soon\"\n");
+    let output = cf_ddns(&invalid, &["--diff-only"]);
+    assert_eq!(output.status.code(), Some(1), "{output:?}");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Invalid configuration"), "{stderr}");
+    let empty = config("diff.toml", "");
+    let output = cf_ddns(&empty, &["--diff-only", "--interval", "60"]);
+    assert_eq!(output.status.code(), Some(1), "{output:?}");
+
+    let output = cf_ddns(&empty, &["--diff-only"]);
+    assert_eq!(output.status.code(), Some(0), "{output:?}");
+}